@@ -1,9 +1,17 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::{Debug, Display},
+    fs::File,
+    io::{self, Write},
+    ops::Range,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
 };
 
-use rand::{random_range};
+use rand::{Rng, SeedableRng, random_range, rngs::StdRng};
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
 pub enum LocationValue {
@@ -11,6 +19,30 @@ pub enum LocationValue {
     Empty,
     Can,
     Wall,
+    /// A valuable can, worth `RewardConfig::gold_pickup`.
+    Gold,
+    /// An open cell that refills the robot's energy to the environment's
+    /// energy budget when it moves onto it.
+    Recharge,
+}
+
+impl LocationValue {
+    /// Whether the robot can pick up whatever is in this cell.
+    pub fn is_collectible(&self) -> bool {
+        matches!(self, LocationValue::Can | LocationValue::Gold)
+    }
+
+    /// Position of this value in `all_locations`.
+    fn index(&self) -> usize {
+        use LocationValue::*;
+        match self {
+            Empty => 0,
+            Can => 1,
+            Wall => 2,
+            Gold => 3,
+            Recharge => 4,
+        }
+    }
 }
 
 impl From<i32> for LocationValue {
@@ -19,6 +51,8 @@ impl From<i32> for LocationValue {
         match value {
             0 => Empty,
             1 => Can,
+            3 => Gold,
+            4 => Recharge,
             _ => Wall,
         }
     }
@@ -31,6 +65,8 @@ impl From<LocationValue> for String {
             Empty => "E".to_string(),
             Can => "C".to_string(),
             Wall => "W".to_string(),
+            Gold => "G".to_string(),
+            Recharge => "R".to_string(),
         }
     }
 }
@@ -41,18 +77,79 @@ impl Display for LocationValue {
             LocationValue::Empty => "E",
             LocationValue::Can => "C",
             LocationValue::Wall => "W",
+            LocationValue::Gold => "G",
+            LocationValue::Recharge => "R",
         };
 
         write!(f, "{}", out_str)
     }
 }
 
+impl FromStr for LocationValue {
+    type Err = ParseError;
+
+    /// Parse the letter `Display` writes: E, C, W, G or R.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use LocationValue::*;
+        match s {
+            "E" => Ok(Empty),
+            "C" => Ok(Can),
+            "W" => Ok(Wall),
+            "G" => Ok(Gold),
+            "R" => Ok(Recharge),
+            _ => Err(ParseError::UnknownLocation(s.to_string())),
+        }
+    }
+}
+
+/// Ways parsing a location or percept from text can fail.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The text isn't one of the letters `LocationValue` displays as.
+    UnknownLocation(String),
+    /// A percept needs exactly five fields.
+    FieldCount { found: usize },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnknownLocation(s) => write!(f, "unknown location value {:?}", s),
+            ParseError::FieldCount { found } => {
+                write!(f, "expected 5 percept fields, found {}", found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 fn all_locations() -> Vec<LocationValue> {
     use LocationValue::*;
-    vec![Empty, Can, Wall]
+    vec![Empty, Can, Wall, Gold, Recharge]
+}
+
+/// The values percepts can contain: every location value, or all but
+/// recharge cells, which read as empty unless `Environment::recharge_sensing`
+/// is on.
+fn sensed_locations(recharge_sensing: bool) -> Vec<LocationValue> {
+    all_locations()
+        .into_iter()
+        .filter(|value| recharge_sensing || *value != LocationValue::Recharge)
+        .collect()
 }
 
-#[derive(PartialEq, Eq, Hash, Clone)]
+/// Number of distinct percepts, i.e. rows in the Q-matrix, with or without
+/// diagonal and recharge sensing.
+fn number_of_possible_percepts(diagonal_sensing: bool, recharge_sensing: bool) -> usize {
+    let sensed_cells = match diagonal_sensing {
+        true => 9,
+        false => 5,
+    };
+    sensed_locations(recharge_sensing).len().pow(sensed_cells)
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub enum Action {
     MoveNorth,
     MoveSouth,
@@ -80,6 +177,22 @@ impl Display for Action {
     }
 }
 
+impl Action {
+    /// Parse a single-letter action key as used by `Display`: n, s, e, w or p,
+    /// case-insensitively.
+    pub fn from_key(key: &str) -> Option<Action> {
+        use Action::*;
+        match key.trim().to_ascii_lowercase().as_str() {
+            "n" => Some(MoveNorth),
+            "s" => Some(MoveSouth),
+            "e" => Some(MoveEast),
+            "w" => Some(MoveWest),
+            "p" => Some(PickUpCan),
+            _ => None,
+        }
+    }
+}
+
 impl From<usize> for Action {
     fn from(value: usize) -> Self {
         use Action::*;
@@ -106,17 +219,19 @@ impl From<Action> for usize {
     }
 }
 
-fn random_action() -> Action {
-    Action::from(random_range(0..5))
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Default, Debug)]
 pub struct Percept {
     pub current: LocationValue,
     pub north: LocationValue,
     pub south: LocationValue,
     pub east: LocationValue,
     pub west: LocationValue,
+    /// Diagonal neighbors, only sensed when `Environment::diagonal_sensing` is
+    /// on.
+    pub north_east: Option<LocationValue>,
+    pub north_west: Option<LocationValue>,
+    pub south_east: Option<LocationValue>,
+    pub south_west: Option<LocationValue>,
 }
 
 impl Display for Percept {
@@ -125,29 +240,235 @@ impl Display for Percept {
             f,
             "C: {}; N: {}; S: {}; E: {}; W: {};",
             self.current, self.north, self.south, self.east, self.west
-        )
+        )?;
+        if let Some([ne, nw, se, sw]) = self.diagonals() {
+            write!(f, " NE: {}; NW: {}; SE: {}; SW: {};", ne, nw, se, sw)?;
+        }
+        Ok(())
+    }
+}
+
+impl Percept {
+    /// Row of the Q-matrix for this percept under the default `FullPercept`
+    /// abstraction. Matches the indices in `Robot::percept_map` without needing
+    /// a lookup.
+    ///
+    /// A percept with diagonals indexes the larger table of a robot built with
+    /// `Robot::new_diagonal`. Percepts with recharge cells in them belong to
+    /// robots whose `FullPercept` has `recharge_sensing` on, and are indexed
+    /// by that abstraction rather than here.
+    pub fn index(&self) -> usize {
+        self.index_with_base(sensed_locations(false).len())
+    }
+
+    /// `index` for a table with `base` location values per sensed cell.
+    fn index_with_base(&self, base: usize) -> usize {
+        let mut index = [self.north, self.south, self.east, self.west, self.current]
+            .iter()
+            .fold(0, |acc, value| acc * base + value.index());
+        if let Some(diagonals) = self.diagonals() {
+            index = diagonals
+                .iter()
+                .fold(index, |acc, value| acc * base + value.index());
+        }
+        index
+    }
+
+    /// The diagonal neighbors `[north_east, north_west, south_east,
+    /// south_west]`, if they were sensed. Any that are missing from a partly
+    /// filled percept count as empty.
+    pub fn diagonals(&self) -> Option<[LocationValue; 4]> {
+        let diagonals = [
+            self.north_east,
+            self.north_west,
+            self.south_east,
+            self.south_west,
+        ];
+        match diagonals.iter().any(|d| d.is_some()) {
+            true => Some(diagonals.map(|d| d.unwrap_or_default())),
+            false => None,
+        }
+    }
+
+    /// Number of the five fields (current, north, south, east and west) on
+    /// which the two percepts agree. Diagonals are ignored.
+    pub fn similarity(&self, other: &Percept) -> u8 {
+        [
+            self.current == other.current,
+            self.north == other.north,
+            self.south == other.south,
+            self.east == other.east,
+            self.west == other.west,
+        ]
+        .iter()
+        .filter(|matches| **matches)
+        .count() as u8
+    }
+
+    /// One-hot encoding of the percept for linear function approximation. Each
+    /// of current, north, south, east and west (in that order) gets three
+    /// slots: empty, collectible and wall. Cans and gold share the collectible
+    /// slot, and recharge cells, being open, the empty one.
+    pub fn features(&self) -> [f32; 15] {
+        let mut features = [0.0; 15];
+        for (i, value) in [self.current, self.north, self.south, self.east, self.west]
+            .iter()
+            .enumerate()
+        {
+            let slot = match value {
+                LocationValue::Empty | LocationValue::Recharge => 0,
+                LocationValue::Can | LocationValue::Gold => 1,
+                LocationValue::Wall => 2,
+            };
+            features[i * 3 + slot] = 1.0;
+        }
+        features
+    }
+
+    /// Parse the `Current,North,South,East,West` columns of a weights CSV row,
+    /// as written by `Robot::weights_csv`.
+    pub fn from_csv_fields(fields: &[&str]) -> Result<Percept, ParseError> {
+        let [current, north, south, east, west] = fields else {
+            return Err(ParseError::FieldCount {
+                found: fields.len(),
+            });
+        };
+        Ok(Percept {
+            current: current.parse()?,
+            north: north.parse()?,
+            south: south.parse()?,
+            east: east.parse()?,
+            west: west.parse()?,
+            ..Default::default()
+        })
+    }
+
+    /// Draw a percept uniformly at random from the ones reachable on an open
+    /// grid (see `is_reachable`) without recharge sensing.
+    pub fn random_reachable(rng: &mut impl Rng) -> Percept {
+        let locations = sensed_locations(false);
+        let mut sample = || locations[rng.random_range(0..locations.len())];
+
+        loop {
+            let p = Percept {
+                current: sample(),
+                north: sample(),
+                south: sample(),
+                east: sample(),
+                west: sample(),
+                ..Default::default()
+            };
+            if p.is_reachable() {
+                return p;
+            }
+        }
+    }
+
+    /// Whether this percept can actually be observed on an open grid of side
+    /// two or more, one with no interior walls and a `sensor_range` of 1:
+    /// the robot never stands on a wall, and since walls only come from the
+    /// boundary, opposite neighbors can't both be walls. Interior walls or a
+    /// longer sensor range make more percepts possible; see
+    /// `is_reachable_on`.
+    pub fn is_reachable(&self) -> bool {
+        use LocationValue::*;
+        self.is_reachable_on(false)
+            && !(self.north == Wall && self.south == Wall)
+            && !(self.east == Wall && self.west == Wall)
+    }
+
+    /// Like `is_reachable` when `open_grid` is set. Otherwise walls can be
+    /// anywhere, for example either side of a corridor, and the only
+    /// impossible percepts are the ones with the robot standing on a wall.
+    pub fn is_reachable_on(&self, open_grid: bool) -> bool {
+        match open_grid {
+            true => self.is_reachable(),
+            false => self.current != LocationValue::Wall,
+        }
+    }
+}
+
+/// Maps percepts to rows of a robot's Q-matrix. Percepts sharing a row are
+/// treated as the same state, so an abstraction can ignore fields it deems
+/// irrelevant.
+pub trait StateAbstraction: Send + Sync {
+    /// The row for the percept, less than `num_states`.
+    fn abstract_index(&self, p: &Percept) -> usize;
+    fn num_states(&self) -> usize;
+    /// Whether the percepts being abstracted include diagonal neighbors.
+    fn diagonal_sensing(&self) -> bool {
+        false
+    }
+    /// Whether the percepts being abstracted can contain recharge cells.
+    fn recharge_sensing(&self) -> bool {
+        false
+    }
+}
+
+/// The default abstraction: every distinct percept is its own state, indexed
+/// by `Percept::index`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FullPercept {
+    pub diagonal_sensing: bool,
+    /// Whether to give recharge cells states of their own, for environments
+    /// with `recharge_sensing` on. This takes the table from 4^5 to 5^5 rows,
+    /// or from 4^9 to 5^9 (about 2 million) with diagonal sensing.
+    pub recharge_sensing: bool,
+}
+
+impl StateAbstraction for FullPercept {
+    fn abstract_index(&self, p: &Percept) -> usize {
+        p.index_with_base(sensed_locations(self.recharge_sensing).len())
+    }
+
+    fn num_states(&self) -> usize {
+        number_of_possible_percepts(self.diagonal_sensing, self.recharge_sensing)
+    }
+
+    fn diagonal_sensing(&self) -> bool {
+        self.diagonal_sensing
+    }
+
+    fn recharge_sensing(&self) -> bool {
+        self.recharge_sensing
     }
 }
 
-/// Create a hash map mapping percepts to usize
-fn generate_percept_map() -> HashMap<Percept, usize> {
+/// Create a hash map mapping every percept, with or without diagonals as the
+/// abstraction expects, to its row in the Q-matrix.
+fn generate_percept_map(abstraction: &dyn StateAbstraction) -> HashMap<Percept, usize> {
+    let diagonal_sensing = abstraction.diagonal_sensing();
+    let locations = sensed_locations(abstraction.recharge_sensing());
     let mut out = HashMap::new();
-    let mut index: usize = 0;
 
-    for north in all_locations() {
-        for south in all_locations() {
-            for east in all_locations() {
-                for west in all_locations() {
-                    for current in all_locations() {
+    for &north in &locations {
+        for &south in &locations {
+            for &east in &locations {
+                for &west in &locations {
+                    for &current in &locations {
                         let p = Percept {
                             north,
                             south,
                             east,
                             west,
                             current,
+                            ..Default::default()
                         };
-                        out.insert(p, index);
-                        index += 1;
+                        if !diagonal_sensing {
+                            out.insert(p.clone(), abstraction.abstract_index(&p));
+                            continue;
+                        }
+                        for diagonals in diagonal_combinations(&locations) {
+                            let [ne, nw, se, sw] = diagonals.map(Some);
+                            let p = Percept {
+                                north_east: ne,
+                                north_west: nw,
+                                south_east: se,
+                                south_west: sw,
+                                ..p.clone()
+                            };
+                            out.insert(p.clone(), abstraction.abstract_index(&p));
+                        }
                     }
                 }
             }
@@ -157,13 +478,337 @@ fn generate_percept_map() -> HashMap<Percept, usize> {
     out
 }
 
-#[derive(Default)]
+/// Every assignment of the given location values to the four diagonal
+/// neighbors.
+fn diagonal_combinations(locations: &[LocationValue]) -> Vec<[LocationValue; 4]> {
+    (0..locations.len().pow(4))
+        .map(|mut n| {
+            let mut out = [LocationValue::Empty; 4];
+            for value in out.iter_mut().rev() {
+                *value = locations[n % locations.len()];
+                n /= locations.len();
+            }
+            out
+        })
+        .collect()
+}
+
+/// Where the robot is placed when a randomized environment is created.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum StartPosition {
+    #[default]
+    Random,
+    Fixed(usize, usize),
+    /// The (0, 0) corner.
+    Corner,
+    /// The middle cell, rounding down on even-sized grids.
+    Center,
+}
+
+impl StartPosition {
+    /// Resolve this start position to coordinates on a grid of the given size.
+    pub fn coordinates(&self, grid_dimension: usize) -> (usize, usize) {
+        match *self {
+            StartPosition::Random => (
+                random_range(0..grid_dimension),
+                random_range(0..grid_dimension),
+            ),
+            StartPosition::Fixed(x, y) => {
+                assert!(
+                    x < grid_dimension && y < grid_dimension,
+                    "start position ({}, {}) is outside a grid of size {}",
+                    x,
+                    y,
+                    grid_dimension
+                );
+                (x, y)
+            }
+            StartPosition::Corner => (0, 0),
+            StartPosition::Center => (grid_dimension / 2, grid_dimension / 2),
+        }
+    }
+}
+
+/// How many cans a randomized environment starts an episode with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum CanCount {
+    Fixed(usize),
+    /// Drawn uniformly from `min..=max` each episode.
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+}
+
+impl CanCount {
+    /// Draw a can count. Panics if a uniform range is empty.
+    pub fn sample(&self, rng: &mut impl Rng) -> usize {
+        match *self {
+            CanCount::Fixed(n) => n,
+            CanCount::Uniform { min, max } => {
+                assert!(min <= max, "empty can count range {}..={}", min, max);
+                rng.random_range(min..=max)
+            }
+        }
+    }
+}
+
+impl From<usize> for CanCount {
+    fn from(n: usize) -> Self {
+        CanCount::Fixed(n)
+    }
+}
+
+/// Reward amounts for each kind of outcome.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RewardConfig {
+    /// Picking up a can.
+    pub can_pickup: f32,
+    /// Picking up gold.
+    pub gold_pickup: f32,
+    /// Trying to pick up a can on an empty cell, or a pickup that fails.
+    pub empty_pickup: f32,
+    /// Trying to pick up a can while standing on a wall cell, which can only
+    /// happen if the robot is placed or pushed there.
+    pub wall_pickup: f32,
+    /// Bumping into a wall.
+    pub crash: f32,
+    /// Moving successfully to a neighboring cell.
+    pub move_penalty: f32,
+    /// Moving onto a cell visited within the last
+    /// `Environment::revisit_window` moves.
+    pub revisit_penalty: f32,
+    /// Extra reward for the first successful pickup in each cell, which only
+    /// matters when `Environment::consume_cans` is off.
+    pub novelty_bonus: f32,
+    /// Taking any action other than a pickup while standing on a can.
+    pub on_can_bonus: f32,
+}
+
+impl Default for RewardConfig {
+    fn default() -> Self {
+        RewardConfig {
+            can_pickup: 10.0,
+            gold_pickup: 25.0,
+            empty_pickup: -1.0,
+            wall_pickup: -1.0,
+            crash: -5.0,
+            move_penalty: 0.0,
+            revisit_penalty: 0.0,
+            novelty_bonus: 0.0,
+            on_can_bonus: 0.0,
+        }
+    }
+}
+
+impl RewardConfig {
+    /// Check for reward settings that invite degenerate policies. In strict
+    /// mode the first problem found is returned as an error; otherwise every
+    /// problem is returned as a warning for the caller to report.
+    pub fn validate(&self, strict: bool) -> Result<Vec<RewardConfigError>, RewardConfigError> {
+        let mut problems = vec![];
+        if self.empty_pickup >= 0.0 && self.can_pickup > 0.0 {
+            problems.push(RewardConfigError::ProfitableWastedPickup {
+                empty_pickup: self.empty_pickup,
+            });
+        }
+
+        match (strict, problems.is_empty()) {
+            (true, false) => Err(problems.remove(0)),
+            _ => Ok(problems),
+        }
+    }
+}
+
+/// Problems `RewardConfig::validate` can find.
+#[derive(Debug, PartialEq)]
+pub enum RewardConfigError {
+    /// Picking up nothing isn't penalized, so the robot can spam pickups.
+    ProfitableWastedPickup { empty_pickup: f32 },
+}
+
+impl Display for RewardConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RewardConfigError::ProfitableWastedPickup { empty_pickup } => write!(
+                f,
+                "empty_pickup is {}, so wasted pickups aren't penalized; it should be negative",
+                empty_pickup
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RewardConfigError {}
+
+/// Fraction of an episode's steps spent on wasted pickups above which
+/// `Environment::dominated_by_wasted_pickups` flags it.
+pub const WASTED_PICKUP_DOMINANCE: f32 = 0.5;
+
+/// The components of a single step's reward. Only the components that apply
+/// to the action are nonzero, and they add up to the step's reward.
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct RewardBreakdown {
+    /// Picking up a can or gold.
+    pub can_pickup: f32,
+    pub wasted_pickup: f32,
+    /// Hitting a wall, or falling into a pit.
+    pub crash: f32,
+    pub move_penalty: f32,
+    /// Extra terms nudging the robot's behavior, such as the revisit penalty.
+    pub shaping: f32,
+}
+
+impl RewardBreakdown {
+    pub fn total(&self) -> f32 {
+        self.can_pickup + self.wasted_pickup + self.crash + self.move_penalty + self.shaping
+    }
+
+    /// Add each of `other`'s components to this one's.
+    pub fn accumulate(&mut self, other: &RewardBreakdown) {
+        self.can_pickup += other.can_pickup;
+        self.wasted_pickup += other.wasted_pickup;
+        self.crash += other.crash;
+        self.move_penalty += other.move_penalty;
+        self.shaping += other.shaping;
+    }
+}
+
+/// Computes the reward for taking an action in an environment. The
+/// environment's side effects (crash counting, terminal states) are applied
+/// before this is called, and reward clipping after.
+pub trait RewardFn: Send + Sync {
+    fn reward(&self, env: &Environment, a: &Action) -> f32;
+}
+
+/// The reward structure described by the environment's `reward_config`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ConfigReward;
+
+impl RewardFn for ConfigReward {
+    fn reward(&self, env: &Environment, a: &Action) -> f32 {
+        env.reward_breakdown(a).total()
+    }
+}
+
+/// What happens when the robot tries to move off the edge of the grid.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum EdgeBehavior {
+    /// The robot bumps into a wall, stays put and is penalized.
+    #[default]
+    Wall,
+    /// The robot falls off, receiving `penalty` as its reward and ending the
+    /// episode.
+    Pit { penalty: f32 },
+}
+
+#[derive(Clone)]
 pub struct Environment {
     pub grid_dimension: usize,
     pub initial_number_of_cans: usize,
     robot_coordinates: (usize, usize),
     pub crash_count: usize,
+    /// Number of pickups this episode that didn't collect anything.
+    pub wasted_pickups: usize,
     grid: Vec<Vec<LocationValue>>,
+    /// Number of cans on the grid, kept up to date as cells change so
+    /// `count_cans` doesn't have to scan the grid.
+    cans_remaining: usize,
+    /// Where `reset` draws each episode's `initial_number_of_cans` from. When
+    /// `None`, the current `initial_number_of_cans` is reused.
+    pub can_count: Option<CanCount>,
+    pub reward_config: RewardConfig,
+    /// Number of previous positions remembered for the revisit penalty.
+    pub revisit_window: usize,
+    recent_positions: VecDeque<(usize, usize)>,
+    /// Range each step's total reward is clamped to, if any.
+    pub reward_clip: Option<(f32, f32)>,
+    pub edge_behavior: EdgeBehavior,
+    /// Whether moving north/south past the edge wraps around to the other
+    /// side (the first coordinate).
+    pub wrap_x: bool,
+    /// Whether moving east/west past the edge wraps around to the other side
+    /// (the second coordinate).
+    pub wrap_y: bool,
+    /// Whether bumping into a wall ends the episode.
+    pub crash_is_terminal: bool,
+    /// Whether trying to pick up a can where there is none ends the episode.
+    pub terminate_on_wasted_pickup: bool,
+    /// Chance that picking up a can actually removes it. A failed pickup earns
+    /// the wasted-pickup reward and leaves the can in place.
+    pub pickup_success_prob: f32,
+    /// Whether a successful pickup removes the can or gold from its cell. When
+    /// off, the same cell can be picked up from again and again.
+    pub consume_cans: bool,
+    /// Cells the robot has successfully picked up from this episode.
+    pub picked_cells: HashSet<(usize, usize)>,
+    /// Outcome of a pickup already sampled by `calculate_reward`, consumed by
+    /// the following `transition_state`.
+    pending_pickup: Option<bool>,
+    /// Set once the episode has reached a terminal state.
+    pub done: bool,
+    /// Random source for stochastic dynamics such as failed pickups.
+    rng: StdRng,
+    /// Reward function used by `calculate_reward`. Shared between clones.
+    pub reward_fn: Arc<dyn RewardFn>,
+    /// Whether percepts include the four diagonal neighbors. Robots acting on
+    /// such percepts need to be built with `Robot::new_diagonal`.
+    pub diagonal_sensing: bool,
+    /// How many cells away the robot can see in each direction. Each of north,
+    /// south, east and west reports the nearest non-empty cell within range,
+    /// or empty if there is none.
+    pub sensor_range: usize,
+    /// Whether percepts tell recharge cells apart from empty ones. Off by
+    /// default, so robots keep their smaller table; robots acting on percepts
+    /// with it on need a `FullPercept` with `recharge_sensing`.
+    pub recharge_sensing: bool,
+    /// Energy the robot starts each episode with, if energy is tracked. Every
+    /// move uses one unit, moving onto a recharge cell refills it, and the
+    /// episode ends when it runs out. Set with `set_energy_budget`, which also
+    /// fills `energy`.
+    energy_budget: Option<usize>,
+    /// Energy left this episode. Only meaningful with an energy budget.
+    pub energy: usize,
+    /// How many layers of wall `with_wall_border` has put around the grid,
+    /// so `reset` can keep them.
+    border_width: usize,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment {
+            grid_dimension: 0,
+            initial_number_of_cans: 0,
+            robot_coordinates: (0, 0),
+            crash_count: 0,
+            wasted_pickups: 0,
+            grid: vec![],
+            cans_remaining: 0,
+            can_count: None,
+            reward_config: RewardConfig::default(),
+            revisit_window: 0,
+            recent_positions: VecDeque::new(),
+            reward_clip: None,
+            edge_behavior: EdgeBehavior::default(),
+            wrap_x: false,
+            wrap_y: false,
+            crash_is_terminal: false,
+            terminate_on_wasted_pickup: false,
+            pickup_success_prob: 1.0,
+            consume_cans: true,
+            picked_cells: HashSet::new(),
+            pending_pickup: None,
+            done: false,
+            rng: StdRng::from_os_rng(),
+            reward_fn: Arc::new(ConfigReward),
+            diagonal_sensing: false,
+            sensor_range: 1,
+            recharge_sensing: false,
+            energy_budget: None,
+            energy: 0,
+            border_width: 0,
+        }
+    }
 }
 
 impl Environment {
@@ -178,148 +823,683 @@ impl Environment {
             robot_coordinates,
             crash_count: 0,
             grid: vec![vec![LocationValue::Empty; grid_dimension]; grid_dimension],
+            ..Default::default()
         }
     }
 
-    pub fn new_randomized(
+    /// A fully specified environment for scenario tests: an otherwise empty
+    /// grid with the robot at `robot_at` and a can at each of `cans`. Panics
+    /// if any coordinates are off the grid or a can is listed twice.
+    pub fn deterministic(
         grid_dimension: usize,
-        initial_number_of_cans: usize,
+        robot_at: (usize, usize),
+        cans: &[(usize, usize)],
     ) -> Self {
-        let x = random_range(0..grid_dimension);
-        let y = random_range(0..grid_dimension);
+        let on_grid = |(x, y): (usize, usize)| x < grid_dimension && y < grid_dimension;
+        assert!(
+            on_grid(robot_at),
+            "robot position {:?} is outside a grid of size {}",
+            robot_at,
+            grid_dimension
+        );
+
+        let mut env = Environment::new(grid_dimension, cans.len(), robot_at);
+        for &xy in cans {
+            assert!(
+                on_grid(xy),
+                "can at {:?} is outside a grid of size {}",
+                xy,
+                grid_dimension
+            );
+            assert!(
+                env.grid[xy.0][xy.1] != LocationValue::Can,
+                "can at {:?} listed twice",
+                xy
+            );
+            env.set_cell(xy, LocationValue::Can);
+        }
+        env
+    }
+
+    /// A random grid holding `can_count` cans, either a plain number or a
+    /// `CanCount` that `reset` keeps drawing from each episode.
+    pub fn new_randomized(grid_dimension: usize, can_count: impl Into<CanCount>) -> Self {
+        Self::new_randomized_with_start(grid_dimension, can_count, StartPosition::Random)
+    }
 
+    /// Like `new_randomized`, but with the robot placed according to `start`.
+    /// Panics if a fixed start position is off the grid.
+    pub fn new_randomized_with_start(
+        grid_dimension: usize,
+        can_count: impl Into<CanCount>,
+        start: StartPosition,
+    ) -> Self {
+        let can_count = can_count.into();
+        let mut rng = rand::rng();
+        let initial_number_of_cans = can_count.sample(&mut rng);
         Environment {
             grid_dimension,
             initial_number_of_cans,
-            robot_coordinates: (x, y),
+            robot_coordinates: start.coordinates(grid_dimension),
             crash_count: 0,
-            grid: random_grid(grid_dimension, initial_number_of_cans),
+            grid: random_grid(grid_dimension, initial_number_of_cans, &mut rng),
+            cans_remaining: initial_number_of_cans,
+            can_count: Some(can_count),
+            ..Default::default()
         }
     }
 
-    pub fn count_cans(&self) -> usize {
-        self.grid.iter().fold(0_usize, |overall_sum, row| {
-            overall_sum
-                + row.iter().fold(0_usize, |row_sum, space| match space {
-                    LocationValue::Can => row_sum + 1,
-                    LocationValue::Empty => row_sum,
-                    LocationValue::Wall => row_sum,
-                })
-        })
-    }
+    /// Like `new_randomized`, but with the can count, grid, start position and
+    /// the environment's own random source all derived from `seed`.
+    pub fn new_seeded(grid_dimension: usize, can_count: impl Into<CanCount>, seed: u64) -> Self {
+        let can_count = can_count.into();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let initial_number_of_cans = can_count.sample(&mut rng);
+        let grid = random_grid(grid_dimension, initial_number_of_cans, &mut rng);
+        let robot_coordinates = (
+            rng.random_range(0..grid_dimension),
+            rng.random_range(0..grid_dimension),
+        );
 
-    pub fn create_percept(&self) -> Percept {
-        use LocationValue::*;
-        let mut p = Percept {
-            north: Empty,
-            south: Empty,
-            east: Empty,
-            west: Empty,
-            current: Empty,
-        };
+        Environment {
+            grid_dimension,
+            initial_number_of_cans,
+            robot_coordinates,
+            grid,
+            cans_remaining: initial_number_of_cans,
+            can_count: Some(can_count),
+            rng: StdRng::seed_from_u64(rng.random()),
+            ..Default::default()
+        }
+    }
 
-        let (x, y) = self.robot_coordinates;
+    /// Rebuild the environment of an episode from the seed in its
+    /// `EpisodeRecord::env_seed`.
+    pub fn from_episode_seed(
+        seed: u64,
+        grid_dimension: usize,
+        can_count: impl Into<CanCount>,
+    ) -> Self {
+        Self::new_seeded(grid_dimension, can_count, seed)
+    }
 
-        p.current = self.grid[x][y];
+    /// Create an environment whose grid comes from a custom generator, called
+    /// with the grid dimension and number of cans. The robot starts at a random
+    /// cell.
+    pub fn new_with_generator(
+        grid_dimension: usize,
+        initial_number_of_cans: usize,
+        generator: impl Fn(usize, usize) -> Vec<Vec<LocationValue>>,
+    ) -> Self {
+        let grid = generator(grid_dimension, initial_number_of_cans);
+        assert!(
+            grid.len() == grid_dimension && grid.iter().all(|row| row.len() == grid_dimension),
+            "generated grid is not {0}x{0}",
+            grid_dimension
+        );
 
-        if x == 0 {
-            p.south = Wall;
-        } else {
-            p.south = self.grid[x - 1][y];
+        Environment {
+            grid_dimension,
+            initial_number_of_cans,
+            robot_coordinates: StartPosition::Random.coordinates(grid_dimension),
+            cans_remaining: recount_cans(&grid),
+            grid,
+            ..Default::default()
         }
+    }
 
-        if x == self.grid_dimension - 1 {
-            p.north = Wall;
-        } else {
-            p.north = self.grid[x + 1][y];
-        }
+    /// Number of cans left on the grid. Gold isn't counted.
+    pub fn count_cans(&self) -> usize {
+        debug_assert_eq!(self.cans_remaining, recount_cans(&self.grid));
+        self.cans_remaining
+    }
 
-        if y == 0 {
-            p.west = Wall;
-        } else {
-            p.west = self.grid[x][y - 1];
+    /// Put `value` in the cell at `(x, y)`, keeping the can count up to date.
+    pub fn set_cell(&mut self, (x, y): (usize, usize), value: LocationValue) {
+        if self.grid[x][y] == LocationValue::Can {
+            self.cans_remaining -= 1;
         }
+        if value == LocationValue::Can {
+            self.cans_remaining += 1;
+        }
+        self.grid[x][y] = value;
+    }
 
-        if y == self.grid_dimension - 1 {
-            p.east = Wall;
-        } else {
-            p.east = self.grid[y][y + 1];
+    /// Start a new episode on a freshly randomized grid with
+    /// `initial_number_of_cans` cans (drawn anew from `can_count` if set) and
+    /// the robot at a random cell, drawing from the environment's own random
+    /// source. Settings are kept.
+    pub fn reset(&mut self) {
+        if let Some(can_count) = self.can_count {
+            self.initial_number_of_cans = can_count.sample(&mut self.rng);
+        }
+        let inner_dimension = self.grid_dimension - 2 * self.border_width;
+        self.grid = random_grid(inner_dimension, self.initial_number_of_cans, &mut self.rng);
+        for _ in 0..self.border_width {
+            self.grid = surround_with_walls(&self.grid);
         }
+        self.cans_remaining = self.initial_number_of_cans;
+        self.robot_coordinates = (
+            self.border_width + self.rng.random_range(0..inner_dimension),
+            self.border_width + self.rng.random_range(0..inner_dimension),
+        );
+        self.crash_count = 0;
+        self.wasted_pickups = 0;
+        self.recent_positions.clear();
+        self.picked_cells.clear();
+        self.pending_pickup = None;
+        self.done = false;
+        self.energy = self.energy_budget.unwrap_or(0);
+    }
 
-        p
+    /// Whether percepts here follow `Percept::is_reachable`: there are no wall
+    /// cells on the grid and the robot only senses its direct neighbors.
+    pub fn is_open_grid(&self) -> bool {
+        self.sensor_range <= 1
+            && !self
+                .grid
+                .iter()
+                .flatten()
+                .any(|v| *v == LocationValue::Wall)
     }
 
-    /// Determine whether, given the current state grid, the given action would
-    /// cause the robot to crash into the wall
-    fn crash(&self, a: &Action) -> bool {
-        use Action::*;
+    /// Whether there is nothing left on the grid to pick up: no cans and no
+    /// gold.
+    pub fn is_cleared(&self) -> bool {
+        !self
+            .grid
+            .iter()
+            .flatten()
+            .any(|space| space.is_collectible())
+    }
 
+    pub fn create_percept(&self) -> Percept {
         let (x, y) = self.robot_coordinates;
+        let read = |(cx, cy): (usize, usize)| match self.grid[cx][cy] {
+            LocationValue::Recharge if !self.recharge_sensing => LocationValue::Empty,
+            value => value,
+        };
 
-        (*a == MoveEast && y >= self.grid_dimension - 1)
-            || (*a == MoveWest && y == 0)
-            || (*a == MoveNorth && x >= self.grid_dimension - 1)
-            || (*a == MoveSouth && x == 0)
-    }
+        // Look up to `sensor_range` cells away, stopping at the first one that
+        // isn't empty.
+        let sense = |a: Action| {
+            let mut position = (x, y);
+            for _ in 0..self.sensor_range.max(1) {
+                match self.destination_from(position, &a) {
+                    Some(next) if read(next) == LocationValue::Empty => position = next,
+                    Some(next) => return read(next),
+                    None => return LocationValue::Wall,
+                }
+            }
+            LocationValue::Empty
+        };
 
-    /// Given an action and the current state, determine the reward
-    pub fn calculate_reward(&mut self, a: &Action) -> f32 {
-        use Action::*;
+        // Diagonal cells are sensed through the edges like any other, but a
+        // wall cell on the way doesn't hide what's beyond it.
+        let sense_diagonal = |first: Action, second: Action| {
+            let cell = self
+                .edge_destination_from((x, y), &first)
+                .and_then(|via| self.edge_destination_from(via, &second));
+            match (self.diagonal_sensing, cell) {
+                (false, _) => None,
+                (true, Some(cell)) => Some(read(cell)),
+                (true, None) => Some(LocationValue::Wall),
+            }
+        };
 
-        let (x, y) = self.robot_coordinates;
+        Percept {
+            current: read((x, y)),
+            north: sense(Action::MoveNorth),
+            south: sense(Action::MoveSouth),
+            east: sense(Action::MoveEast),
+            west: sense(Action::MoveWest),
+            north_east: sense_diagonal(Action::MoveNorth, Action::MoveEast),
+            north_west: sense_diagonal(Action::MoveNorth, Action::MoveWest),
+            south_east: sense_diagonal(Action::MoveSouth, Action::MoveEast),
+            south_west: sense_diagonal(Action::MoveSouth, Action::MoveWest),
+        }
+    }
 
-        match a {
-            PickUpCan => match self.grid[x][y] {
-                LocationValue::Can => 10.0,
-                _ => -1.0,
-            },
-            _ => match self.crash(a) {
-                true => {
-                    self.crash_count += 1;
-                    -5.0
-                }
-                false => 0.0,
-            },
+    /// A copy of this environment with every collectible removed and the robot
+    /// at `at`, keeping the size, walls, edge and sensing settings but none of
+    /// the episode state.
+    fn cleared_copy(&self, at: (usize, usize)) -> Environment {
+        let grid = self
+            .grid
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|space| match space.is_collectible() {
+                        true => LocationValue::Empty,
+                        false => *space,
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Environment {
+            grid_dimension: self.grid_dimension,
+            robot_coordinates: at,
+            grid,
+            edge_behavior: self.edge_behavior,
+            wrap_x: self.wrap_x,
+            wrap_y: self.wrap_y,
+            diagonal_sensing: self.diagonal_sensing,
+            sensor_range: self.sensor_range,
+            recharge_sensing: self.recharge_sensing,
+            ..Default::default()
         }
     }
 
-    // Given the action and the current state, update the state to reflect the
-    // action.
-    pub fn transition_state(&mut self, a: &Action) {
+    /// The coordinates the robot would end up at after the given action, or
+    /// `None` if the action would take it off a non-wrapping edge or into a
+    /// wall cell.
+    fn destination(&self, a: &Action) -> Option<(usize, usize)> {
+        self.destination_from(self.robot_coordinates, a)
+    }
+
+    /// Like `destination`, but starting from arbitrary coordinates.
+    fn destination_from(&self, from: (usize, usize), a: &Action) -> Option<(usize, usize)> {
+        self.edge_destination_from(from, a)
+            .filter(|(x, y)| self.grid[*x][*y] != LocationValue::Wall)
+    }
+
+    /// The cell an action leads to from the given coordinates, ignoring wall
+    /// cells, or `None` if it leads off a non-wrapping edge.
+    fn edge_destination_from(&self, from: (usize, usize), a: &Action) -> Option<(usize, usize)> {
         use Action::*;
 
-        let (x, y) = self.robot_coordinates;
+        let (x, y) = from;
+        // On a 1x1 grid every move hits an edge, or wraps back to the same
+        // cell.
+        let last = self.grid_dimension.saturating_sub(1);
 
         match *a {
-            MoveNorth => {
-                if x < self.grid_dimension - 1 {
-                    self.robot_coordinates.0 += 1;
-                }
-            }
-            MoveSouth => {
-                if x > 0 {
-                    self.robot_coordinates.0 -= 1;
-                }
-            }
-            MoveEast => {
-                if y < self.grid_dimension - 1 {
-                    self.robot_coordinates.1 += 1;
-                }
-            }
-            MoveWest => {
-                if y > 0 {
-                    self.robot_coordinates.1 -= 1;
+            MoveNorth if x < last => Some((x + 1, y)),
+            MoveNorth if self.wrap_x => Some((0, y)),
+            MoveSouth if x > 0 => Some((x - 1, y)),
+            MoveSouth if self.wrap_x => Some((last, y)),
+            MoveEast if y < last => Some((x, y + 1)),
+            MoveEast if self.wrap_y => Some((x, 0)),
+            MoveWest if y > 0 => Some((x, y - 1)),
+            MoveWest if self.wrap_y => Some((x, last)),
+            PickUpCan => Some((x, y)),
+            _ => None,
+        }
+    }
+
+    /// Check that moving north then south, south then north, east then west
+    /// and west then east each lead back to the starting cell, from every cell
+    /// not on the edge of the grid. Wall cells are ignored. Returns a
+    /// description of the first pair of moves that doesn't.
+    pub fn verify_movement_symmetry(&self) -> Result<(), String> {
+        use Action::*;
+
+        let pairs = [
+            (MoveNorth, MoveSouth),
+            (MoveSouth, MoveNorth),
+            (MoveEast, MoveWest),
+            (MoveWest, MoveEast),
+        ];
+        let interior = 1..self.grid_dimension.saturating_sub(1);
+        for x in interior.clone() {
+            for y in interior.clone() {
+                for (there, back) in &pairs {
+                    let returned = self
+                        .edge_destination_from((x, y), there)
+                        .and_then(|next| self.edge_destination_from(next, back));
+                    if returned != Some((x, y)) {
+                        return Err(format!(
+                            "{:?} then {:?} from {:?} ends at {:?}",
+                            there,
+                            back,
+                            (x, y),
+                            returned
+                        ));
+                    }
                 }
             }
-            PickUpCan => {
-                if self.grid[x][y] == LocationValue::Can {
-                    self.grid[x][y] = LocationValue::Empty;
-                }
+        }
+        Ok(())
+    }
+
+    /// The reward for falling into a pit if the action would take the robot
+    /// off an edge under `EdgeBehavior::Pit`.
+    fn pit_penalty(&self, a: &Action) -> Option<f32> {
+        match self.edge_behavior {
+            EdgeBehavior::Pit { penalty }
+                if self
+                    .edge_destination_from(self.robot_coordinates, a)
+                    .is_none() =>
+            {
+                Some(penalty)
             }
+            _ => None,
         }
     }
 
-}
+    /// Determine whether, given the current state grid, the given action would
+    /// cause the robot to crash into the wall
+    fn crash(&self, a: &Action) -> bool {
+        self.destination(a).is_none()
+    }
+
+    /// Given an action and the current state, determine the reward
+    pub fn calculate_reward(&mut self, a: &Action) -> f32 {
+        if *a == Action::PickUpCan {
+            let (x, y) = self.robot_coordinates;
+            let succeeded = self.grid[x][y].is_collectible() && self.sample_pickup();
+            self.pending_pickup = Some(succeeded);
+            if !succeeded {
+                self.wasted_pickups += 1;
+                self.done |= self.terminate_on_wasted_pickup;
+            }
+        } else if self.pit_penalty(a).is_some() {
+            self.done = true;
+        } else if self.crash(a) {
+            self.crash_count += 1;
+            self.done |= self.crash_is_terminal;
+        }
+
+        let total = self.reward_fn.reward(self, a);
+        if *a != Action::PickUpCan {
+            self.pending_pickup = None;
+        }
+        match self.reward_clip {
+            Some((low, high)) => total.clamp(low, high),
+            None => total,
+        }
+    }
+
+    /// Whether wasted pickups made up more than `WASTED_PICKUP_DOMINANCE` of
+    /// the `steps` taken so far, a sign the robot is farming a profitable
+    /// wasted-pickup reward.
+    pub fn dominated_by_wasted_pickups(&self, steps: usize) -> bool {
+        steps > 0 && self.wasted_pickups as f32 / steps as f32 > WASTED_PICKUP_DOMINANCE
+    }
+
+    /// Whether a pickup attempt on a collectible cell succeeds. Only draws
+    /// from the rng when success isn't certain.
+    fn sample_pickup(&mut self) -> bool {
+        self.pickup_success_prob >= 1.0
+            || self
+                .rng
+                .random_bool(self.pickup_success_prob.max(0.0) as f64)
+    }
+
+    /// Reseed the random source used for stochastic dynamics.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// The novelty bonus for picking up in the robot's cell, or zero if it has
+    /// already been picked up from this episode.
+    fn novelty_bonus(&self) -> f32 {
+        match self.picked_cells.contains(&self.robot_coordinates) {
+            true => 0.0,
+            false => self.reward_config.novelty_bonus,
+        }
+    }
+
+    /// Split the reward the given action would earn into its components. A
+    /// pickup is assumed to succeed unless `calculate_reward` has already
+    /// sampled a failure for it.
+    pub fn reward_breakdown(&self, a: &Action) -> RewardBreakdown {
+        let (x, y) = self.robot_coordinates;
+        let config = &self.reward_config;
+        let mut breakdown = RewardBreakdown::default();
+
+        match a {
+            Action::PickUpCan => match self.grid[x][y] {
+                value if value.is_collectible() && self.pending_pickup == Some(false) => {
+                    breakdown.wasted_pickup = config.empty_pickup
+                }
+                LocationValue::Can => {
+                    breakdown.can_pickup = config.can_pickup;
+                    breakdown.shaping += self.novelty_bonus();
+                }
+                LocationValue::Gold => {
+                    breakdown.can_pickup = config.gold_pickup;
+                    breakdown.shaping += self.novelty_bonus();
+                }
+                LocationValue::Empty | LocationValue::Recharge => {
+                    breakdown.wasted_pickup = config.empty_pickup
+                }
+                LocationValue::Wall => breakdown.wasted_pickup = config.wall_pickup,
+            },
+            _ => match (self.crash(a), self.pit_penalty(a)) {
+                (_, Some(penalty)) => breakdown.crash = penalty,
+                (true, None) => breakdown.crash = config.crash,
+                (false, None) => {
+                    breakdown.move_penalty = config.move_penalty;
+                    if let Some(next) = self.destination(a)
+                        && self.recent_positions.contains(&next)
+                    {
+                        breakdown.shaping += config.revisit_penalty;
+                    }
+                }
+            },
+        }
+        if *a != Action::PickUpCan && self.grid[x][y] == LocationValue::Can {
+            breakdown.shaping += config.on_can_bonus;
+        }
+
+        breakdown
+    }
+
+    // Given the action and the current state, update the state to reflect the
+    // action.
+    pub fn transition_state(&mut self, a: &Action) {
+        use Action::*;
+
+        let (x, y) = self.robot_coordinates;
+
+        match *a {
+            PickUpCan => {
+                if self.grid[x][y].is_collectible() {
+                    let succeeded = match self.pending_pickup.take() {
+                        Some(succeeded) => succeeded,
+                        None => self.sample_pickup(),
+                    };
+                    if succeeded {
+                        self.picked_cells.insert((x, y));
+                        if self.consume_cans {
+                            self.set_cell((x, y), LocationValue::Empty);
+                        }
+                    }
+                }
+            }
+            _ => {
+                let destination = self.destination(a);
+                if let Some(coordinates) = destination {
+                    if self.revisit_window > 0 {
+                        if self.recent_positions.len() == self.revisit_window {
+                            self.recent_positions.pop_front();
+                        }
+                        self.recent_positions.push_back(self.robot_coordinates);
+                    }
+                    self.robot_coordinates = coordinates;
+                }
+
+                if let Some(budget) = self.energy_budget {
+                    self.energy = match destination {
+                        Some((x, y)) if self.grid[x][y] == LocationValue::Recharge => budget,
+                        _ => self.energy.saturating_sub(1),
+                    };
+                    self.done |= self.energy == 0;
+                }
+            }
+        }
+    }
+
+    /// Turn on energy tracking with the given budget, starting full.
+    pub fn set_energy_budget(&mut self, budget: usize) {
+        self.energy_budget = Some(budget);
+        self.energy = budget;
+    }
+
+    /// The energy each episode starts with, if energy is tracked.
+    pub fn energy_budget(&self) -> Option<usize> {
+        self.energy_budget
+    }
+
+    /// The best reward an episode could earn from here: the pickup reward for
+    /// every reachable can and gold, plus the move penalty for each move the
+    /// oracle takes to collect them. The oracle always heads for the nearest
+    /// collectible, so with a move penalty this is a close estimate rather
+    /// than a strict bound. Revisit and crash penalties are ignored, and the
+    /// route stops after `oracle_step_limit` steps.
+    pub fn max_possible_reward(&self) -> f32 {
+        let mut route = self.clone();
+        route.consume_cans = true;
+        route.pickup_success_prob = 1.0;
+
+        let config = &self.reward_config;
+        let mut total = 0.0;
+        for _ in 0..oracle_step_limit(self) {
+            let Some(a) = oracle_action(&route) else {
+                break;
+            };
+            let (x, y) = route.robot_coordinates;
+            total += match (&a, route.grid[x][y]) {
+                (Action::PickUpCan, LocationValue::Gold) => config.gold_pickup,
+                (Action::PickUpCan, _) => config.can_pickup,
+                _ => config.move_penalty,
+            };
+            route.transition_state(&a);
+        }
+        total
+    }
+
+    /// This environment with the grid surrounded by a one-cell border of wall
+    /// cells, so it grows by two in each direction and the robot (along with
+    /// any remembered positions) shifts by one along both axes. The border
+    /// stays in place when the environment is `reset`.
+    pub fn with_wall_border(mut self) -> Self {
+        let shift = |(x, y): (usize, usize)| (x + 1, y + 1);
+        self.grid = surround_with_walls(&self.grid);
+        self.grid_dimension += 2;
+        self.border_width += 1;
+        self.robot_coordinates = shift(self.robot_coordinates);
+        self.recent_positions = self.recent_positions.iter().copied().map(shift).collect();
+        self.picked_cells = self.picked_cells.iter().copied().map(shift).collect();
+        self
+    }
+
+    /// All 8 symmetries of this environment: the grid rotated by 0, 90, 180
+    /// and 270 degrees, each with and without a mirror image, with the robot
+    /// moved along with its cell. The first entry is an unchanged copy.
+    pub fn symmetries(&self) -> Vec<Environment> {
+        let last = self.grid_dimension.saturating_sub(1);
+        let rotate = |(x, y): (usize, usize)| (y, last - x);
+        let mirror = |(x, y): (usize, usize)| (x, last - y);
+
+        let mut out = Vec::with_capacity(8);
+        let mut current = self.clone();
+        for _ in 0..4 {
+            let mut mirrored = current.clone();
+            for x in 0..self.grid_dimension {
+                for y in 0..self.grid_dimension {
+                    let (mx, my) = mirror((x, y));
+                    mirrored.grid[mx][my] = current.grid[x][y];
+                }
+            }
+            mirrored.robot_coordinates = mirror(current.robot_coordinates);
+
+            let mut rotated = current.clone();
+            for x in 0..self.grid_dimension {
+                for y in 0..self.grid_dimension {
+                    let (rx, ry) = rotate((x, y));
+                    rotated.grid[rx][ry] = current.grid[x][y];
+                }
+            }
+            rotated.robot_coordinates = rotate(current.robot_coordinates);
+            // A quarter turn swaps which axis each wrap setting applies to.
+            rotated.wrap_x = current.wrap_y;
+            rotated.wrap_y = current.wrap_x;
+
+            out.push(current);
+            out.push(mirrored);
+            current = rotated;
+        }
+
+        out
+    }
+
+    /// Render the grid with north at the top, marking the robot with `R`,
+    /// cans with `C`, walls with `#` and empty cells with `_`.
+    pub fn render(&self) -> String {
+        let row_strings: Vec<String> = self
+            .grid
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(x, row)| {
+                let space_strings: Vec<&str> = row
+                    .iter()
+                    .enumerate()
+                    .map(|(y, space)| match space {
+                        _ if (x, y) == self.robot_coordinates => "R",
+                        LocationValue::Empty => "_",
+                        LocationValue::Can => "C",
+                        LocationValue::Gold => "G",
+                        LocationValue::Wall => "#",
+                        LocationValue::Recharge => "+",
+                    })
+                    .collect();
+
+                space_strings.join(" ")
+            })
+            .collect();
+
+        row_strings.join("\n")
+    }
+
+    /// Like `render`, but with ANSI colors for terminals: a blue robot, green
+    /// cans, yellow gold, gray walls and cyan recharge cells.
+    #[cfg(feature = "color")]
+    pub fn render_colored(&self) -> String {
+        const RESET: &str = "\x1b[0m";
+
+        let row_strings: Vec<String> = self
+            .grid
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(x, row)| {
+                let space_strings: Vec<String> = row
+                    .iter()
+                    .enumerate()
+                    .map(|(y, space)| match space {
+                        _ if (x, y) == self.robot_coordinates => format!("\x1b[34mR{}", RESET),
+                        LocationValue::Empty => "_".to_string(),
+                        LocationValue::Can => format!("\x1b[32mC{}", RESET),
+                        LocationValue::Gold => format!("\x1b[33mG{}", RESET),
+                        LocationValue::Wall => format!("\x1b[90m#{}", RESET),
+                        LocationValue::Recharge => format!("\x1b[36m+{}", RESET),
+                    })
+                    .collect();
+
+                space_strings.join(" ")
+            })
+            .collect();
+
+        row_strings.join("\n")
+    }
+
+    /// Apply a recorded sequence of actions, returning the reward and the
+    /// resulting robot coordinates for each step.
+    pub fn replay(&mut self, actions: &[Action]) -> Vec<(f32, (usize, usize))> {
+        actions
+            .iter()
+            .map(|a| {
+                let reward_amount = self.calculate_reward(a);
+                self.transition_state(a);
+                (reward_amount, self.robot_coordinates)
+            })
+            .collect()
+    }
+}
 
 impl Debug for Environment {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -343,6 +1523,8 @@ impl Display for Environment {
                     .map(|space| match space {
                         LocationValue::Empty => "_".to_string(),
                         LocationValue::Can => "C".to_string(),
+                        LocationValue::Gold => "G".to_string(),
+                        LocationValue::Recharge => "+".to_string(),
                         _ => "".to_string(),
                     })
                     .collect();
@@ -355,155 +1537,5224 @@ impl Display for Environment {
     }
 }
 
-fn random_grid(dimension: usize, number_of_cans: usize) -> Vec<Vec<LocationValue>> {
-    let mut grid = vec![vec![LocationValue::Empty; dimension]; dimension];
+/// Apply an action to the environment, returning the reward, the resulting
+/// percept and whether the episode is over.
+pub fn step_env(env: &mut Environment, a: &Action) -> (f32, Percept, bool) {
+    let reward_amount = env.calculate_reward(a);
+    env.transition_state(a);
+    (reward_amount, env.create_percept(), env.done)
+}
 
-    let mut cans_assigned = 0_usize;
+/// Like `step_env`, but also returning the reward split into its components.
+/// The breakdown is taken after the reward is calculated, so a sampled pickup
+/// failure is reflected, and before the state moves on.
+pub fn step_env_with_breakdown(
+    env: &mut Environment,
+    a: &Action,
+) -> (f32, RewardBreakdown, Percept, bool) {
+    let reward_amount = env.calculate_reward(a);
+    let breakdown = env.reward_breakdown(a);
+    env.transition_state(a);
+    (reward_amount, breakdown, env.create_percept(), env.done)
+}
 
-    let mut already_assigned: HashSet<(usize, usize)> = HashSet::with_capacity(number_of_cans);
-    let mut xy: (usize, usize);
+/// An environment several threads can step at once. Each step holds the lock
+/// from computing the reward to applying the transition, so two agents can
+/// never both collect the same can.
+#[derive(Clone)]
+pub struct SharedEnvironment {
+    inner: Arc<Mutex<Environment>>,
+}
 
-    while cans_assigned < number_of_cans {
-        xy = (random_range(0..dimension), random_range(0..dimension));
-        if !already_assigned.contains(&xy) {
-            grid[xy.0][xy.1] = LocationValue::Can;
-            already_assigned.insert(xy);
-            cans_assigned += 1;
+impl SharedEnvironment {
+    pub fn new(env: Environment) -> Self {
+        SharedEnvironment {
+            inner: Arc::new(Mutex::new(env)),
         }
     }
 
-    grid
+    fn lock(&self) -> MutexGuard<'_, Environment> {
+        self.inner
+            .lock()
+            .expect("shared environment mutex poisoned")
+    }
+
+    /// Step the environment's own robot, as `step_env` does.
+    pub fn step(&self, a: &Action) -> (f32, Percept, bool) {
+        step_env(&mut self.lock(), a)
+    }
+
+    /// Step an agent that keeps its own position on the shared grid: the
+    /// robot is placed at `position`, stepped, and its new position returned
+    /// along with the usual step results.
+    pub fn step_at(
+        &self,
+        position: (usize, usize),
+        a: &Action,
+    ) -> (f32, Percept, bool, (usize, usize)) {
+        let mut env = self.lock();
+        env.robot_coordinates = position;
+        let (reward_amount, p, done) = step_env(&mut env, a);
+        (reward_amount, p, done, env.robot_coordinates)
+    }
+
+    /// Run `f` with exclusive access to the environment.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Environment) -> R) -> R {
+        f(&mut self.lock())
+    }
 }
 
-#[derive(Default)]
-pub struct Robot {
-    previous_choice: Option<(Percept, Action)>,
-    pub q_matrix: Vec<Vec<f32>>,
-    pub epsilon: f32,
-    pub percept_map: HashMap<Percept, usize>,
+/// Count the cans on a grid by scanning every cell.
+fn recount_cans(grid: &[Vec<LocationValue>]) -> usize {
+    grid.iter()
+        .flatten()
+        .filter(|space| **space == LocationValue::Can)
+        .count()
 }
 
-impl Robot {
-    pub fn new(epsilon: f32) -> Self {
-        let number_of_possible_percepts = 3_usize.pow(5);
-        let number_of_actions = 5;
-        Robot {
-            previous_choice: None,
-            q_matrix: vec![vec![0.0; number_of_actions]; number_of_possible_percepts],
-            epsilon,
-            percept_map: generate_percept_map(),
+/// Ways generating a constrained grid can fail.
+#[derive(Debug, PartialEq)]
+pub enum GridError {
+    /// No layout satisfying the constraints was found within the given
+    /// number of attempts.
+    TooCrowded { attempts: usize },
+}
+
+impl Display for GridError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GridError::TooCrowded { attempts } => write!(
+                f,
+                "couldn't fit the requested layout after {} attempts",
+                attempts
+            ),
         }
     }
+}
 
-    pub fn select_action(&mut self, p: &Percept) -> Action {
-        let r: f32 = random_range(0.0..1.0);
+impl std::error::Error for GridError {}
 
-        let out = match self.epsilon > r || self.all_actions_same(p) {
-            true => random_action(),
-            false => self.max_action_for_percept(p).0,
-        };
+/// Number of layouts the constrained grid generators try before giving up.
+const MAX_GRID_ATTEMPTS: usize = 1000;
 
-        self.previous_choice = Some((p.clone(), out.clone()));
+/// Cells reachable from `start` by orthogonal moves that avoid walls,
+/// without wrapping around the edges.
+fn reachable_cells(grid: &[Vec<LocationValue>], start: (usize, usize)) -> HashSet<(usize, usize)> {
+    let dimension = grid.len();
+    let mut seen: HashSet<(usize, usize)> = HashSet::from([start]);
+    let mut queue: VecDeque<(usize, usize)> = VecDeque::from([start]);
 
-        out
+    while let Some((x, y)) = queue.pop_front() {
+        let neighbors = [
+            (x + 1 < dimension).then(|| (x + 1, y)),
+            x.checked_sub(1).map(|nx| (nx, y)),
+            (y + 1 < dimension).then(|| (x, y + 1)),
+            y.checked_sub(1).map(|ny| (x, ny)),
+        ];
+        for next in neighbors.into_iter().flatten() {
+            if grid[next.0][next.1] != LocationValue::Wall && seen.insert(next) {
+                queue.push_back(next);
+            }
+        }
     }
 
-    pub fn all_actions_same(&self, p: &Percept) -> bool {
-        let percept_index = self.percept_map[p];
-        let actions = &self.q_matrix[percept_index];
+    seen
+}
 
-        actions
-            .iter()
-            .fold((actions[0], true), |acc, score| {
-                (*score, acc.1 && acc.0 == *score)
-            })
-            .1
+/// Generate a grid with `number_of_walls` interior walls and
+/// `number_of_cans` cans, every one of which can be reached from `start`.
+/// Wall layouts are redrawn until enough cells are reachable, and cans are
+/// then placed only on reachable cells.
+pub fn random_grid_solvable(
+    dimension: usize,
+    number_of_cans: usize,
+    number_of_walls: usize,
+    start: (usize, usize),
+) -> Result<Vec<Vec<LocationValue>>, GridError> {
+    assert!(
+        number_of_walls < dimension * dimension,
+        "no room left for the robot"
+    );
+
+    for _ in 0..MAX_GRID_ATTEMPTS {
+        let mut grid = vec![vec![LocationValue::Empty; dimension]; dimension];
+        let mut walls_assigned = 0_usize;
+        while walls_assigned < number_of_walls {
+            let xy = (random_range(0..dimension), random_range(0..dimension));
+            if xy != start && grid[xy.0][xy.1] != LocationValue::Wall {
+                grid[xy.0][xy.1] = LocationValue::Wall;
+                walls_assigned += 1;
+            }
+        }
+
+        let reachable: Vec<(usize, usize)> = reachable_cells(&grid, start).into_iter().collect();
+        if reachable.len() < number_of_cans {
+            continue;
+        }
+
+        for index in rand::seq::index::sample(&mut rand::rng(), reachable.len(), number_of_cans) {
+            let (x, y) = reachable[index];
+            grid[x][y] = LocationValue::Can;
+        }
+
+        return Ok(grid);
     }
 
-    pub fn max_action_for_percept(&self, p: &Percept) -> (Action, f32) {
-        let percept_index = self.percept_map[p];
+    Err(GridError::TooCrowded {
+        attempts: MAX_GRID_ATTEMPTS,
+    })
+}
 
-        let actions = &self.q_matrix[percept_index];
+/// Generate a grid with `number_of_cans` cans, no two of which are
+/// orthogonally adjacent. Each attempt visits the cells in a random order,
+/// skipping any next to a can already placed.
+pub fn random_grid_spread(
+    dimension: usize,
+    number_of_cans: usize,
+) -> Result<Vec<Vec<LocationValue>>, GridError> {
+    use rand::seq::SliceRandom;
+
+    let mut cells: Vec<(usize, usize)> = (0..dimension)
+        .flat_map(|x| (0..dimension).map(move |y| (x, y)))
+        .collect();
 
-        let mut candidates: Vec<usize> = vec![];
+    for _ in 0..MAX_GRID_ATTEMPTS {
+        let mut grid = vec![vec![LocationValue::Empty; dimension]; dimension];
+        let mut cans_assigned = 0_usize;
+        cells.shuffle(&mut rand::rng());
 
-        let mut max_score = actions[0];
-        for (i, item) in actions.iter().enumerate() {
-            if *item == max_score {
-                candidates.push(i);
-            } else if *item > max_score {
-                candidates.clear();
-                candidates.push(i);
-                max_score = *item;
+        for (x, y) in &cells {
+            if cans_assigned == number_of_cans {
+                break;
+            }
+            let neighbors = [
+                (x + 1, *y),
+                (x.wrapping_sub(1), *y),
+                (*x, y + 1),
+                (*x, y.wrapping_sub(1)),
+            ];
+            let crowded = neighbors.iter().any(|(nx, ny)| {
+                grid.get(*nx).and_then(|row| row.get(*ny)) == Some(&LocationValue::Can)
+            });
+            if !crowded {
+                grid[*x][*y] = LocationValue::Can;
+                cans_assigned += 1;
             }
         }
 
-        assert!(!candidates.is_empty());
-        let choice_index = random_range(0..candidates.len());
-        let out_action: Action = candidates[choice_index].into();
-        (out_action, max_score)
+        if cans_assigned == number_of_cans {
+            return Ok(grid);
+        }
     }
 
-    pub fn reward(
-        &mut self,
-        reward_amount: f32,
-        eta: f32,
-        gamma: f32,
-        resulting_percept: &Percept,
-    ) {
-        if let Some((p, a)) = &self.previous_choice {
-            // TODO fix this unwrap nightmare
-            // TODO Add epsilon and deeper update logic
-            let percept_index = self.percept_map[p];
-            let action_index = usize::from(a.clone());
-            let current_q = self.q_matrix[percept_index][action_index];
+    Err(GridError::TooCrowded {
+        attempts: MAX_GRID_ATTEMPTS,
+    })
+}
 
-            let max_aprime_q = self.max_action_for_percept(resulting_percept).1;
-            let new_value = current_q + eta * (reward_amount + gamma * max_aprime_q - current_q);
+/// A copy of `grid` with a one-cell border of wall cells around it.
+fn surround_with_walls(grid: &[Vec<LocationValue>]) -> Vec<Vec<LocationValue>> {
+    let dimension = grid.len() + 2;
+    let mut out = vec![vec![LocationValue::Wall; dimension]; dimension];
+    for (x, row) in grid.iter().enumerate() {
+        out[x + 1][1..=row.len()].copy_from_slice(row);
+    }
+    out
+}
+
+fn random_grid(
+    dimension: usize,
+    number_of_cans: usize,
+    rng: &mut impl Rng,
+) -> Vec<Vec<LocationValue>> {
+    assert!(
+        number_of_cans <= dimension * dimension,
+        "can't fit {} cans on a {}x{} grid",
+        number_of_cans,
+        dimension,
+        dimension
+    );
+    let mut grid = vec![vec![LocationValue::Empty; dimension]; dimension];
+
+    let mut cans_assigned = 0_usize;
+
+    let mut already_assigned: HashSet<(usize, usize)> = HashSet::with_capacity(number_of_cans);
+    let mut xy: (usize, usize);
 
-            self.q_matrix[percept_index][action_index] = new_value;
+    while cans_assigned < number_of_cans {
+        xy = (
+            rng.random_range(0..dimension),
+            rng.random_range(0..dimension),
+        );
+        if !already_assigned.contains(&xy) {
+            grid[xy.0][xy.1] = LocationValue::Can;
+            already_assigned.insert(xy);
+            cans_assigned += 1;
         }
     }
+
+    grid
 }
 
-#[test]
-fn test_environment_creation() {
-    let mut env = Environment {
-        grid_dimension: 10,
-        initial_number_of_cans: 20,
-        ..Default::default()
-    };
-    env.grid = random_grid(env.grid_dimension, env.initial_number_of_cans);
+/// Serde adapter storing non-finite floats as `null`, and reading `null` back
+/// as NaN.
+mod nullable_f32 {
+    use serde::{Deserialize, Deserializer, Serializer};
 
-    assert_eq!(env.initial_number_of_cans, env.count_cans());
+    pub fn serialize<S: Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        match value.is_finite() {
+            true => serializer.serialize_f32(*value),
+            false => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        Ok(Option::<f32>::deserialize(deserializer)?.unwrap_or(f32::NAN))
+    }
 }
 
-#[test]
-fn test_percept_creation() {
-    use LocationValue::*;
-    let mut rob = Robot::new(0.1);
-    let mut env = Environment::new(3, 0, (0, 0));
+/// Summary of a single training or testing episode.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeRecord {
+    pub episode_id: usize,
+    pub episode_reward: f32,
+    pub crash_count: usize,
+    /// Mean reward of the (up to) 100 episodes before this one. NaN for the
+    /// first episode, which is serialized as `null`.
+    #[serde(with = "nullable_f32")]
+    pub running_average: f32,
+    /// Wall-clock time the episode took, or 0 if timing was disabled.
+    pub duration_micros: u64,
+    /// Whether the episode was cut short for exceeding
+    /// `TrainParams::reward_cap`.
+    pub hit_reward_cap: bool,
+    /// Whether the episode was cut short after
+    /// `TrainParams::max_idle_steps` consecutive zero-reward steps.
+    pub hit_idle_limit: bool,
+    /// Seed the episode's environment was built from, if it was seeded.
+    /// `Environment::from_episode_seed` rebuilds it.
+    pub env_seed: Option<u64>,
+    /// Mean of the greedy Q-value of each percept acted on this episode,
+    /// taken once the episode is over. 0 if no steps were taken.
+    #[serde(with = "nullable_f32")]
+    pub mean_greedy_q: f32,
+    /// Fraction of the episode's steps where `select_action` explored because
+    /// of epsilon. Random choices forced by a row of equal Q-values don't
+    /// count. 0 if no steps were taken.
+    #[serde(with = "nullable_f32")]
+    pub exploration_fraction: f32,
+    /// Number of Q-values that moved by more than `SIGNIFICANT_Q_CHANGE` over
+    /// the episode.
+    pub q_updates: usize,
+    /// Each reward component summed over the episode. These add up to
+    /// `episode_reward` unless the environment clips rewards or uses a custom
+    /// reward function.
+    pub reward_totals: RewardBreakdown,
+    /// Whether wasted pickups made up most of the episode's steps, as judged
+    /// by `Environment::dominated_by_wasted_pickups`.
+    pub wasted_pickup_dominated: bool,
+}
 
-    env.grid[0][1] = Can;
+impl EpisodeRecord {
+    /// Episode reward divided by one more than the number of crashes, trading
+    /// off performance against safety.
+    pub fn reward_per_crash(&self) -> f32 {
+        self.episode_reward / (1 + self.crash_count) as f32
+    }
+}
 
-    let mut out_p = env.create_percept();
-    assert_eq!(out_p.south, Wall);
-    assert_eq!(out_p.west, Wall);
-    assert_eq!(out_p.current, Empty);
-    assert_eq!(out_p.north, Can);
+/// `EpisodeRecord::reward_per_crash` over a whole run: the total reward
+/// divided by one more than the total number of crashes.
+pub fn run_reward_per_crash(records: &[EpisodeRecord]) -> f32 {
+    let reward: f32 = records.iter().map(|e| e.episode_reward).sum();
+    let crashes: usize = records.iter().map(|e| e.crash_count).sum();
+    reward / (1 + crashes) as f32
+}
 
-    env.robot_coordinates = (2, 2);
+/// Sum of `rewards` with the reward at step `t` discounted by `gamma^t`.
+pub fn discounted_return(rewards: &[f32], gamma: f32) -> f32 {
+    rewards.iter().rev().fold(0.0, |acc, r| r + gamma * acc)
+}
 
-    out_p = env.create_percept();
+/// Settings for a run of episodes driven by `train`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrainParams {
+    pub grid_dimension: usize,
+    /// How many cans each training grid starts with.
+    pub can_count: CanCount,
+    pub n_episodes: usize,
+    /// Maximum number of steps in each episode.
+    pub m_steps: usize,
+    pub eta: f32,
+    pub gamma: f32,
+    /// Factor epsilon is multiplied by every 50 episodes.
+    pub epsilon_decay: f32,
+    /// Floor epsilon never decays below. An epsilon that starts lower is left
+    /// alone.
+    #[serde(default)]
+    pub min_epsilon: f32,
+    /// Whether to record each episode's wall-clock duration.
+    pub time_episodes: bool,
+    /// Seeds to generate training grids from, cycled through in order. When
+    /// `None` every episode gets an unseeded random grid.
+    pub grid_seeds: Option<Range<u64>>,
+    /// Cumulative episode reward beyond which an episode is cut short, to
+    /// catch runaway reward shaping.
+    pub reward_cap: Option<f32>,
+    /// Seed each episode's environment is derived from with `episode_seed`,
+    /// when `grid_seeds` isn't set.
+    pub base_seed: Option<u64>,
+    /// Number of consecutive zero-reward steps after which an episode is cut
+    /// short. Crashes and pickups reset the count even when they are worth
+    /// nothing.
+    pub max_idle_steps: Option<usize>,
+}
 
-    assert_eq!(out_p.north, Wall);
-    assert_eq!(out_p.east, Wall);
-    assert_eq!(out_p.current, Empty);
-    assert_eq!(out_p.south, Empty);
+impl TrainParams {
+    /// Epsilon after one decay step from `epsilon`, held at `min_epsilon`.
+    fn decayed_epsilon(&self, epsilon: f32) -> f32 {
+        (epsilon * self.epsilon_decay).max(self.min_epsilon.min(epsilon))
+    }
+
+    /// The epsilon `train` explores with during `episode_id` for a robot that
+    /// started at `initial`.
+    pub fn epsilon_at(&self, initial: f32, episode_id: usize) -> f32 {
+        (0..episode_id / 50).fold(initial, |epsilon, _| self.decayed_epsilon(epsilon))
+    }
 }
 
-#[test]
-fn test_percept_map_creation() {
-    let map = generate_percept_map();
-    assert_eq!(map.len(), 3_usize.pow(5));
+impl Default for TrainParams {
+    fn default() -> Self {
+        TrainParams {
+            grid_dimension: 10,
+            can_count: CanCount::Fixed(50),
+            n_episodes: 5000,
+            m_steps: 200,
+            eta: 0.2,
+            gamma: 0.9,
+            epsilon_decay: 0.99,
+            min_epsilon: 0.0,
+            time_episodes: true,
+            grid_seeds: None,
+            reward_cap: None,
+            base_seed: None,
+            max_idle_steps: None,
+        }
+    }
+}
+
+/// Everything needed to share a training run: what went in and what came
+/// out.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrainingArtifact {
+    pub params: TrainParams,
+    /// Seed the robot was created with.
+    pub seed: u64,
+    pub records: Vec<EpisodeRecord>,
+    /// The robot's Q-matrix once training finished.
+    pub final_q: Vec<Vec<f32>>,
+}
+
+impl TrainingArtifact {
+    /// Write the artifact to `path` as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    /// Read an artifact written by `save`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+}
+
+/// The environment seed for an episode of a run with the given base seed.
+pub fn episode_seed(base_seed: u64, episode_id: usize) -> u64 {
+    StdRng::seed_from_u64(base_seed.wrapping_add(episode_id as u64)).random()
+}
+
+/// Reasons `train` can stop before running every episode.
+#[derive(Debug, PartialEq)]
+pub enum TrainError {
+    /// A Q-value became NaN or infinite during the given episode.
+    NonFiniteQ { episode_id: usize },
+}
+
+impl Display for TrainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrainError::NonFiniteQ { episode_id } => write!(
+                f,
+                "Q-values became non-finite during episode {}; check eta and gamma",
+                episode_id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TrainError {}
+
+/// Run `params.n_episodes` episodes on freshly randomized grids, updating the
+/// robot's Q-values after every step. Stops with an error as soon as an
+/// update produces a non-finite Q-value.
+pub fn train(robot: &mut Robot, params: &TrainParams) -> Result<Vec<EpisodeRecord>, TrainError> {
+    let mut episodes = EpisodeIter::new(robot, params);
+    let records: Vec<EpisodeRecord> = episodes.by_ref().collect();
+    match episodes.error.take() {
+        Some(error) => Err(error),
+        None => Ok(records),
+    }
+}
+
+/// The episodes `train` runs, one per call to `next`, so long runs can be
+/// aggregated without keeping every record. Iteration stops after
+/// `params.n_episodes` episodes, or early if an update produces a non-finite
+/// Q-value, in which case the error is left in `error`.
+pub struct EpisodeIter<'a> {
+    robot: &'a mut Robot,
+    params: &'a TrainParams,
+    episode_id: usize,
+    /// Rewards of the (up to) 100 most recent episodes, for the running
+    /// average.
+    recent_rewards: VecDeque<f32>,
+    /// Why iteration stopped early, if it did.
+    pub error: Option<TrainError>,
+}
+
+impl<'a> EpisodeIter<'a> {
+    pub fn new(robot: &'a mut Robot, params: &'a TrainParams) -> Self {
+        EpisodeIter {
+            robot,
+            params,
+            episode_id: 0,
+            recent_rewards: VecDeque::with_capacity(100),
+            error: None,
+        }
+    }
+
+    /// The seed, if any, and the starting environment of episode `episode_id`.
+    fn episode_start(&self, episode_id: usize) -> (Option<u64>, Environment) {
+        let params = self.params;
+        let env_seed = match (&params.grid_seeds, params.base_seed) {
+            (Some(seeds), _) => {
+                assert!(!seeds.is_empty(), "grid_seeds is empty");
+                Some(seeds.start + episode_id as u64 % (seeds.end - seeds.start))
+            }
+            (None, Some(base_seed)) => Some(episode_seed(base_seed, episode_id)),
+            (None, None) => None,
+        };
+        let environment = match env_seed {
+            Some(seed) => {
+                Environment::from_episode_seed(seed, params.grid_dimension, params.can_count)
+            }
+            None => Environment::new_randomized(params.grid_dimension, params.can_count),
+        };
+        (env_seed, environment)
+    }
+
+    fn run_episode(&mut self) -> Result<EpisodeRecord, TrainError> {
+        let start = Instant::now();
+        let (env_seed, mut environment) = self.episode_start(self.episode_id);
+        let robot = &mut *self.robot;
+        let params = self.params;
+        let episode_id = self.episode_id;
+
+        let mut episode_reward: f32 = 0.0;
+        let mut hit_reward_cap = false;
+        let mut hit_idle_limit = false;
+        let mut idle_steps = 0;
+        let mut visited: Vec<Percept> = vec![];
+        let mut reward_totals = RewardBreakdown::default();
+        let explored_before = robot.exploratory_choices;
+        let q_before = robot.q_matrix.clone();
+
+        for _ in 0..params.m_steps {
+            let p = environment.create_percept();
+            let a = robot.select_action(&p);
+            visited.push(p);
+            let crashes_before = environment.crash_count;
+            let (reward_amount, breakdown, resulting_p, done) =
+                step_env_with_breakdown(&mut environment, &a);
+            reward_totals.accumulate(&breakdown);
+            episode_reward += reward_amount;
+            robot.reward(reward_amount, params.eta, params.gamma, &resulting_p, done);
+
+            // Planning can write anywhere in the matrix, not just the entry
+            // being updated, so check all of it.
+            if robot.divergence_check().is_some() {
+                return Err(TrainError::NonFiniteQ { episode_id });
+            }
+
+            if params.reward_cap.is_some_and(|cap| episode_reward > cap) {
+                hit_reward_cap = true;
+                break;
+            }
+
+            let crashed = environment.crash_count > crashes_before;
+            idle_steps = match reward_amount == 0.0 && !crashed && a != Action::PickUpCan {
+                true => idle_steps + 1,
+                false => 0,
+            };
+            if params
+                .max_idle_steps
+                .is_some_and(|limit| idle_steps >= limit)
+            {
+                hit_idle_limit = true;
+                break;
+            }
+
+            if done {
+                break;
+            }
+        }
+        robot.end_episode(params.gamma);
+        if robot.divergence_check().is_some() {
+            return Err(TrainError::NonFiniteQ { episode_id });
+        }
+        let wasted_pickup_dominated = environment.dominated_by_wasted_pickups(visited.len());
+        let mean_greedy_q = match visited.len() {
+            0 => 0.0,
+            n => visited.iter().map(|p| robot.greedy_q(p)).sum::<f32>() / n as f32,
+        };
+        let exploration_fraction = match visited.len() {
+            0 => 0.0,
+            n => (robot.exploratory_choices - explored_before) as f32 / n as f32,
+        };
+        let q_updates = q_before
+            .iter()
+            .flatten()
+            .zip(robot.q_matrix.iter().flatten())
+            .filter(|(before, after)| (*after - *before).abs() > SIGNIFICANT_Q_CHANGE)
+            .count();
+
+        let sum: f32 = self.recent_rewards.iter().sum();
+        let running_average = sum / self.recent_rewards.len() as f32;
+
+        let record = EpisodeRecord {
+            episode_id,
+            episode_reward,
+            crash_count: environment.crash_count,
+            running_average,
+            duration_micros: match params.time_episodes {
+                true => start.elapsed().as_micros() as u64,
+                false => 0,
+            },
+            hit_reward_cap,
+            hit_idle_limit,
+            env_seed,
+            mean_greedy_q,
+            exploration_fraction,
+            q_updates,
+            reward_totals,
+            wasted_pickup_dominated,
+        };
+
+        // reduce epsilon every 50 episodes
+        if (episode_id + 1).is_multiple_of(50) {
+            robot.epsilon = params.decayed_epsilon(robot.epsilon);
+        }
+
+        Ok(record)
+    }
+}
+
+impl Iterator for EpisodeIter<'_> {
+    type Item = EpisodeRecord;
+
+    fn next(&mut self) -> Option<EpisodeRecord> {
+        if self.episode_id >= self.params.n_episodes || self.error.is_some() {
+            return None;
+        }
+
+        match self.run_episode() {
+            Ok(record) => {
+                if self.recent_rewards.len() == 100 {
+                    self.recent_rewards.pop_front();
+                }
+                self.recent_rewards.push_back(record.episode_reward);
+                self.episode_id += 1;
+                Some(record)
+            }
+            Err(error) => {
+                self.error = Some(error);
+                None
+            }
+        }
+    }
+}
+
+/// Smallest change in a Q-value over an episode that
+/// `EpisodeRecord::q_updates` counts.
+pub const SIGNIFICANT_Q_CHANGE: f32 = 1e-6;
+
+/// Starting epsilon of the robots `train_many` creates.
+pub const INITIAL_EPSILON: f32 = 0.1;
+
+/// Train `runs` fresh robots with the same parameters, returning each run's
+/// episode records. Run `i` uses a robot seeded with `i` and starting at
+/// `INITIAL_EPSILON`.
+pub fn train_many(
+    params: &TrainParams,
+    runs: usize,
+) -> Result<Vec<Vec<EpisodeRecord>>, TrainError> {
+    (0..runs)
+        .map(|run| train(&mut Robot::with_seed(INITIAL_EPSILON, run as u64), params))
+        .collect()
+}
+
+/// Grid search over `(eta, gamma)` pairs: each pair is trained with
+/// `train_many` on `base_params` with its eta and gamma swapped in, and
+/// returned alongside the final running average reward, averaged over the
+/// `runs`.
+pub fn sweep(
+    param_grid: &[(f32, f32)],
+    base_params: &TrainParams,
+    runs: usize,
+) -> Result<Vec<(f32, f32, f32)>, TrainError> {
+    param_grid
+        .iter()
+        .map(|&(eta, gamma)| {
+            let params = TrainParams {
+                eta,
+                gamma,
+                ..base_params.clone()
+            };
+            let results = train_many(&params, runs)?;
+            let finals: f32 = results
+                .iter()
+                .map(|records| records.last().map_or(f32::NAN, |e| e.running_average))
+                .sum();
+            Ok((eta, gamma, finals / runs as f32))
+        })
+        .collect()
+}
+
+/// Run the robot's greedy policy for up to `params.m_steps` steps on a grid
+/// built from each seed in `eval_seeds`, returning each episode's reward. No
+/// learning happens. In debug builds, panics if the evaluation seeds overlap
+/// the training seeds in `params.grid_seeds`.
+pub fn evaluate_heldout(robot: &Robot, params: &TrainParams, eval_seeds: Range<u64>) -> Vec<f32> {
+    if let Some(train_seeds) = &params.grid_seeds {
+        debug_assert!(
+            eval_seeds.end <= train_seeds.start || train_seeds.end <= eval_seeds.start,
+            "evaluation seeds {:?} overlap training seeds {:?}",
+            eval_seeds,
+            train_seeds
+        );
+    }
+
+    eval_seeds
+        .map(|seed| {
+            let mut environment =
+                Environment::new_seeded(params.grid_dimension, params.can_count, seed);
+            let mut episode_reward = 0.0;
+            for _ in 0..params.m_steps {
+                let a = robot.first_best_action(&environment.create_percept());
+                let (reward_amount, _, done) = step_env(&mut environment, &a);
+                episode_reward += reward_amount;
+                if done {
+                    break;
+                }
+            }
+            episode_reward
+        })
+        .collect()
+}
+
+/// Run the robot's greedy policy on each grid until it is cleared or `cap`
+/// steps have been taken, returning the number of steps used per grid. No
+/// learning happens during the rollouts.
+pub fn rollout_lengths(robot: &mut Robot, grids: &mut [Environment], cap: usize) -> Vec<usize> {
+    grids
+        .iter_mut()
+        .map(|environment| {
+            let mut steps = 0;
+            while steps < cap && !environment.is_cleared() && !environment.done {
+                let a = robot.select_greedy_action(&environment.create_percept());
+                step_env(environment, &a);
+                steps += 1;
+            }
+            steps
+        })
+        .collect()
+}
+
+/// Average number of greedy steps the robot needs to clear a random
+/// `dim`×`dim` grid holding `cans` cans, over `trials` grids. A grid that is
+/// not cleared within `cap` steps counts as `cap`. Ties are broken by lowest
+/// action index and no learning happens.
+pub fn expected_clear_time(
+    robot: &Robot,
+    dim: usize,
+    cans: usize,
+    trials: usize,
+    cap: usize,
+) -> f32 {
+    assert!(trials > 0, "need at least one trial");
+    let total: usize = (0..trials)
+        .map(|_| {
+            let mut environment = Environment::new_randomized(dim, cans);
+            let mut steps = 0;
+            while steps < cap && !environment.is_cleared() && !environment.done {
+                let a = robot.first_best_action(&environment.create_percept());
+                step_env(&mut environment, &a);
+                steps += 1;
+            }
+            match environment.is_cleared() {
+                true => steps,
+                false => cap,
+            }
+        })
+        .sum();
+    total as f32 / trials as f32
+}
+
+/// Long-run fraction of time the greedy policy spends in each cell of a
+/// can-free copy of `env` (keeping its size, walls and edge settings), found by
+/// `iters` rounds of power iteration from a uniform distribution over the open
+/// cells. A move that is blocked, or falls into a pit, leaves the robot in
+/// place. Ties are broken by lowest action index.
+pub fn stationary_distribution(robot: &Robot, env: &Environment, iters: usize) -> Vec<Vec<f32>> {
+    let dim = env.grid_dimension;
+    let scratch = env.cleared_copy((0, 0));
+
+    let mut next_cell = vec![vec![(0, 0); dim]; dim];
+    let mut distribution = vec![vec![0.0; dim]; dim];
+    let open: Vec<(usize, usize)> = (0..dim)
+        .flat_map(|x| (0..dim).map(move |y| (x, y)))
+        .filter(|(x, y)| scratch.grid[*x][*y] != LocationValue::Wall)
+        .collect();
+
+    for (x, y) in &open {
+        let mut at = scratch.clone();
+        at.robot_coordinates = (*x, *y);
+        let a = robot.first_best_action(&at.create_percept());
+        next_cell[*x][*y] = at.destination(&a).unwrap_or((*x, *y));
+        distribution[*x][*y] = 1.0 / open.len() as f32;
+    }
+
+    for _ in 0..iters {
+        let mut next = vec![vec![0.0; dim]; dim];
+        for (x, y) in &open {
+            let (nx, ny) = next_cell[*x][*y];
+            next[nx][ny] += distribution[*x][*y];
+        }
+        distribution = next;
+    }
+
+    distribution
+}
+
+/// Follow the greedy policy from every cell of a can-free copy of `env`
+/// (keeping its size, walls and edge settings) and return each distinct cycle
+/// of positions the robot ends up repeating forever. A robot stuck in place
+/// shows up as a cycle of length one. Ties are broken by lowest action index.
+pub fn detect_policy_cycles(robot: &Robot, env: &Environment) -> Vec<Vec<(usize, usize)>> {
+    let mut cycles: Vec<Vec<(usize, usize)>> = vec![];
+
+    for x in 0..env.grid_dimension {
+        for y in 0..env.grid_dimension {
+            let mut scratch = env.cleared_copy((x, y));
+
+            let mut path: Vec<(usize, usize)> = vec![(x, y)];
+            loop {
+                let a = robot.first_best_action(&scratch.create_percept());
+                if step_env(&mut scratch, &a).2 {
+                    break;
+                }
+
+                let position = scratch.robot_coordinates;
+                if let Some(start) = path.iter().position(|visited| *visited == position) {
+                    let mut cycle = path[start..].to_vec();
+                    let smallest = (0..cycle.len()).min_by_key(|i| cycle[*i]).unwrap();
+                    cycle.rotate_left(smallest);
+                    if !cycles.contains(&cycle) {
+                        cycles.push(cycle);
+                    }
+                    break;
+                }
+                path.push(position);
+            }
+        }
+    }
+
+    cycles
+}
+
+/// Draw from the standard normal distribution with the Box-Muller transform.
+fn standard_normal(rng: &mut impl Rng) -> f32 {
+    let u1: f32 = 1.0 - rng.random_range(0.0..1.0);
+    let u2: f32 = rng.random_range(0.0..1.0);
+    (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+/// Format a float for CSV the same way on every platform, spelling out
+/// non-finite values as `nan`, `inf` and `-inf` so they parse back with
+/// `str::parse`.
+pub fn csv_float(value: f32) -> String {
+    match value {
+        _ if value.is_nan() => "nan".to_string(),
+        f32::INFINITY => "inf".to_string(),
+        f32::NEG_INFINITY => "-inf".to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Quote a CSV field if it contains a comma, quote or line break, doubling any
+/// quotes inside it.
+pub fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// Write one CSV line, quoting fields as needed.
+pub fn write_csv_row(out: &mut impl Write, fields: &[String]) -> io::Result<()> {
+    let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+    writeln!(out, "{}", line.join(","))
+}
+
+/// Write one JSON object per episode, one per line, with a key for every
+/// `EpisodeRecord` field. Non-finite floats are written as `null`.
+pub fn write_episodes_jsonl(path: impl AsRef<Path>, records: &[EpisodeRecord]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    for e in records {
+        serde_json::to_writer(&mut file, e)?;
+        writeln!(file)?;
+    }
+
+    Ok(())
+}
+
+/// The action a fully-informed robot would take: pick up if standing on
+/// something collectible, otherwise take the first step of a shortest path to
+/// the nearest collectible cell. Equally short paths are resolved in action
+/// order. Returns `None` if nothing collectible can be reached.
+pub fn oracle_action(env: &Environment) -> Option<Action> {
+    let start = env.robot_coordinates;
+    if env.grid[start.0][start.1].is_collectible() {
+        return Some(Action::PickUpCan);
+    }
+
+    let mut first_moves: HashMap<(usize, usize), Action> = HashMap::new();
+    let mut queue: VecDeque<(usize, usize)> = VecDeque::new();
+    first_moves.insert(start, Action::PickUpCan);
+    queue.push_back(start);
+
+    while let Some(position) = queue.pop_front() {
+        for a in all_actions()
+            .into_iter()
+            .filter(|a| *a != Action::PickUpCan)
+        {
+            let Some(next) = env.destination_from(position, &a) else {
+                continue;
+            };
+            if first_moves.contains_key(&next) {
+                continue;
+            }
+
+            let first_move = match position == start {
+                true => a,
+                false => first_moves[&position].clone(),
+            };
+            if env.grid[next.0][next.1].is_collectible() {
+                return Some(first_move);
+            }
+            first_moves.insert(next, first_move);
+            queue.push_back(next);
+        }
+    }
+
+    None
+}
+
+/// Most steps the oracle gets to clear `env`: enough to walk across every cell
+/// and pick up once for each of them. Grids whose cans never run out, because
+/// pickups fail or don't consume them, would otherwise keep it busy forever.
+pub fn oracle_step_limit(env: &Environment) -> usize {
+    let cells = env.grid_dimension * env.grid_dimension;
+    cells * (cells + 1)
+}
+
+/// Fraction of steps on which the robot's greedy action matches the oracle's,
+/// over the states visited while the oracle clears a copy of each grid, for at
+/// most `oracle_step_limit` steps per grid. Ties in the robot's Q-values are
+/// broken by lowest action index. Returns 0.0 if the oracle takes no steps at
+/// all, i.e. none of the grids has anything collectible.
+pub fn action_accuracy(robot: &Robot, grids: &[Environment]) -> f32 {
+    let mut steps = 0_usize;
+    let mut matches = 0_usize;
+
+    for grid in grids {
+        let mut environment = grid.clone();
+        for _ in 0..oracle_step_limit(grid) {
+            let Some(a) = oracle_action(&environment) else {
+                break;
+            };
+            steps += 1;
+            if robot.first_best_action(&environment.create_percept()) == a {
+                matches += 1;
+            }
+            if step_env(&mut environment, &a).2 {
+                break;
+            }
+        }
+    }
+
+    match steps {
+        0 => 0.0,
+        _ => matches as f32 / steps as f32,
+    }
+}
+
+/// Largest running-average slope, in reward per episode, that `has_plateaued`
+/// still treats as flat.
+pub const PLATEAU_SLOPE_THRESHOLD: f32 = 0.01;
+
+/// Whether learning has leveled off: fits a least-squares line to the running
+/// averages of the last `window` episodes and checks that its slope is below
+/// `PLATEAU_SLOPE_THRESHOLD` in magnitude. Returns false until there are
+/// `window` episodes with a finite running average.
+pub fn has_plateaued(records: &[EpisodeRecord], window: usize) -> bool {
+    let points: Vec<(f32, f32)> = records
+        .iter()
+        .filter(|r| r.running_average.is_finite())
+        .map(|r| (r.episode_id as f32, r.running_average))
+        .collect();
+    if window < 2 || points.len() < window {
+        return false;
+    }
+
+    let points = &points[points.len() - window..];
+    let n = window as f32;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f32>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f32>() / n;
+    let covariance: f32 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f32 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    (covariance / variance).abs() < PLATEAU_SLOPE_THRESHOLD
+}
+
+/// Area under the episode reward curve, using the trapezoidal rule with unit
+/// spacing between episodes, divided by the number of episodes. Agents that
+/// learn faster score higher.
+pub fn learning_auc(records: &[EpisodeRecord]) -> f32 {
+    if records.is_empty() {
+        return 0.0;
+    }
+
+    let area: f32 = records
+        .windows(2)
+        .map(|pair| (pair[0].episode_reward + pair[1].episode_reward) / 2.0)
+        .sum();
+
+    area / records.len() as f32
+}
+
+/// Id of the first episode whose running average reached `threshold`, or
+/// `None` if none did.
+pub fn episodes_to_threshold(records: &[EpisodeRecord], threshold: f32) -> Option<usize> {
+    records
+        .iter()
+        .find(|e| e.running_average >= threshold)
+        .map(|e| e.episode_id)
+}
+
+/// Running sum of the per-episode regret `oracle_rewards[i] -
+/// records[i].episode_reward`. Panics if the lengths differ.
+pub fn cumulative_regret(records: &[EpisodeRecord], oracle_rewards: &[f32]) -> Vec<f32> {
+    assert_eq!(
+        records.len(),
+        oracle_rewards.len(),
+        "need one oracle reward per episode"
+    );
+    records
+        .iter()
+        .zip(oracle_rewards)
+        .scan(0.0, |regret, (e, oracle)| {
+            *regret += oracle - e.episode_reward;
+            Some(*regret)
+        })
+        .collect()
+}
+
+/// Strategy used by `Robot::select_action` to choose between exploring and
+/// exploiting.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum SelectionPolicy {
+    /// Take a random action with probability epsilon, otherwise the greedy one.
+    #[default]
+    EpsilonGreedy,
+    /// Pick `argmax_a Q(s,a) + c * sqrt(ln(N_s) / n_{s,a})`, where `N_s` is the
+    /// number of times the state was seen and `n_{s,a}` the number of times the
+    /// action was taken in it. Untried actions are always preferred.
+    Ucb1 { c: f32 },
+}
+
+/// How `Robot::reward` bootstraps the value of the resulting state.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum UpdateRule {
+    /// Use the largest Q-value of the next state.
+    #[default]
+    QLearning,
+    /// Use the expected Q-value of the next state under the epsilon-greedy
+    /// policy.
+    ExpectedSarsa,
+    /// Q-learning, but with the TD-error clamped to `[-delta, delta]` so rare
+    /// large rewards can't move a Q-value too far in one update.
+    HuberClipped { delta: f32 },
+    /// First-visit Monte Carlo: buffer the episode and, in
+    /// `Robot::end_episode`, move each visited state-action's Q-value towards
+    /// the running average of the discounted returns that followed it.
+    MonteCarlo,
+    /// Q-learning followed, after each real update, by `planning_steps`
+    /// simulated updates drawn from the robot's transition model, which is
+    /// switched on automatically.
+    DynaQ { planning_steps: usize },
+}
+
+/// The learning rate `Robot::reward` uses for each update.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum EtaSchedule {
+    /// The `eta` passed to `reward`.
+    #[default]
+    Constant,
+    /// `1 / (1 + n)`, where `n` is the number of times the updated action has
+    /// been selected in its percept, counting the selection being updated.
+    /// The `eta` passed to `reward` is ignored.
+    VisitCount,
+}
+
+/// Running mean and variance of a stream of values, using Welford's algorithm.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct RunningStats {
+    pub count: u64,
+    pub mean: f32,
+    m2: f32,
+}
+
+impl RunningStats {
+    pub fn push(&mut self, value: f32) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Population variance of the values seen so far.
+    pub fn variance(&self) -> f32 {
+        match self.count {
+            0 => 0.0,
+            n => self.m2 / n as f32,
+        }
+    }
+
+    pub fn std_dev(&self) -> f32 {
+        self.variance().sqrt()
+    }
+}
+
+/// Return the index of the first largest entry.
+fn argmax_first(values: &[f32]) -> usize {
+    let mut best = 0;
+    for (i, item) in values.iter().enumerate() {
+        if *item > values[best] {
+            best = i;
+        }
+    }
+    best
+}
+
+/// Return the index and value of the largest entry, breaking ties at random.
+fn argmax_random_tie(values: &[f32], rng: &mut impl Rng) -> (usize, f32) {
+    let (candidates, max_score) = max_candidates(values);
+    let choice_index = rng.random_range(0..candidates.len());
+    (candidates[choice_index], max_score)
+}
+
+/// Indices of every entry equal to the largest value, and that value.
+fn max_candidates(values: &[f32]) -> (Vec<usize>, f32) {
+    let mut candidates: Vec<usize> = vec![];
+
+    let mut max_score = values[0];
+    for (i, item) in values.iter().enumerate() {
+        if *item == max_score {
+            candidates.push(i);
+        } else if *item > max_score {
+            candidates.clear();
+            candidates.push(i);
+            max_score = *item;
+        }
+    }
+
+    assert!(!candidates.is_empty());
+    (candidates, max_score)
+}
+
+/// How a robot's greedy choice in `Robot::select_action` picks between actions
+/// sharing the best Q-value. `Robot::max_action_for_percept`, which draws
+/// nothing, takes the lowest index wherever this would pick at random.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum TieBreak {
+    /// Pick one of them at random.
+    #[default]
+    Random,
+    /// Pick up whenever picking up is one of them, otherwise pick at random.
+    PreferPickup,
+}
+
+/// A Q-matrix compressed to one signed byte per value. Each value is stored as
+/// `round((q - offset) / scale) - 128`, so dequantizing is accurate to within
+/// `scale / 2`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct QuantizedPolicy {
+    pub values: Vec<Vec<i8>>,
+    pub scale: f32,
+    pub offset: f32,
+}
+
+impl QuantizedPolicy {
+    pub fn dequantize(&self) -> Vec<Vec<f32>> {
+        self.values
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|q| (*q as f32 + 128.0) * self.scale + self.offset)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Counts of the percepts observed after taking each action in each percept.
+#[derive(Clone, Default, Debug)]
+pub struct TransitionModel {
+    pub counts: HashMap<(Percept, Action), HashMap<Percept, u32>>,
+    /// Statistics of the rewards received for each percept and action.
+    pub rewards: HashMap<(Percept, Action), RunningStats>,
+    /// Every percept and action seen, in the order first seen.
+    pub seen: Vec<(Percept, Action)>,
+}
+
+impl TransitionModel {
+    /// Record one observed transition and the reward it earned.
+    pub fn record(
+        &mut self,
+        p: &Percept,
+        a: &Action,
+        reward_amount: f32,
+        resulting_percept: &Percept,
+    ) {
+        let key = (p.clone(), a.clone());
+        if !self.counts.contains_key(&key) {
+            self.seen.push(key.clone());
+        }
+        *self
+            .counts
+            .entry(key.clone())
+            .or_default()
+            .entry(resulting_percept.clone())
+            .or_insert(0) += 1;
+        self.rewards.entry(key).or_default().push(reward_amount);
+    }
+
+    /// Draw a previously seen percept and action uniformly, then a resulting
+    /// percept according to the observed frequencies. Returns the pair, its
+    /// mean reward and the resulting percept, or `None` if nothing has been
+    /// recorded.
+    fn sample(&self, rng: &mut impl Rng) -> Option<(&Percept, &Action, f32, Percept)> {
+        if self.seen.is_empty() {
+            return None;
+        }
+        let (p, a) = &self.seen[rng.random_range(0..self.seen.len())];
+
+        let probs = self.transition_probs(p, a);
+        let mut r: f32 = rng.random_range(0.0..1.0);
+        let mut next = &probs[probs.len() - 1].0;
+        for (candidate, prob) in &probs {
+            if r < *prob {
+                next = candidate;
+                break;
+            }
+            r -= prob;
+        }
+
+        let mean_reward = self.rewards[&(p.clone(), a.clone())].mean;
+        Some((p, a, mean_reward, next.clone()))
+    }
+
+    /// Observed frequency of each resulting percept after taking `a` in `p`,
+    /// most likely first. Empty if the pair has never been seen.
+    pub fn transition_probs(&self, p: &Percept, a: &Action) -> Vec<(Percept, f32)> {
+        let Some(outcomes) = self.counts.get(&(p.clone(), a.clone())) else {
+            return vec![];
+        };
+
+        let total: u32 = outcomes.values().sum();
+        let mut probs: Vec<(Percept, f32)> = outcomes
+            .iter()
+            .map(|(next, count)| (next.clone(), *count as f32 / total as f32))
+            .collect();
+        probs.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then_with(|| a.0.index().cmp(&b.0.index()))
+        });
+        probs
+    }
+}
+
+/// How a Q-value has become non-finite.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DivergenceKind {
+    PositiveInfinity,
+    NegativeInfinity,
+    NaN,
+}
+
+/// A tabular Q-learning agent.
+///
+/// All randomness used in action selection is drawn from the robot's own
+/// `rng`, so two robots built with `Robot::with_seed` and fed the same percepts
+/// choose the same actions. Each `select_action` call draws, in order:
+///
+/// 1. under epsilon-greedy, one `f32` to decide whether to explore;
+/// 2. either one `f32` picking the exploratory action from
+///    `exploration_weights`, or, only if several actions share the best score,
+///    one `usize` breaking the tie (unless `TieBreak::PreferPickup` settles
+///    it). A single best action draws nothing.
+///
+/// Q-value updates in `reward` draw nothing, except under `UpdateRule::DynaQ`,
+/// where each planning step draws a `usize` choosing the transition to replay
+/// and an `f32` choosing its outcome.
+pub struct Robot {
+    previous_choice: Option<(Percept, Action)>,
+    pub q_matrix: Vec<Vec<f32>>,
+    pub epsilon: f32,
+    /// Row of `q_matrix` for every percept, as given by `abstraction`.
+    pub percept_map: HashMap<Percept, usize>,
+    abstraction: Arc<dyn StateAbstraction>,
+    pub selection_policy: SelectionPolicy,
+    pub update_rule: UpdateRule,
+    pub eta_schedule: EtaSchedule,
+    pub tie_break: TieBreak,
+    /// Whether `select_action` skips moves into walls it can see.
+    pub action_masking: bool,
+    /// Whether the robot lives on an open grid, so analyses and exports can
+    /// skip the percepts that can't occur there (see `Percept::is_reachable`).
+    /// Clear it for environments where `Environment::is_open_grid` is false.
+    /// On by default.
+    pub open_grid: bool,
+    /// Number of times each action has been selected in each percept, indexed
+    /// like `q_matrix`.
+    pub action_counts: Vec<Vec<u32>>,
+    /// Number of times `select_action` has been called with each percept.
+    pub visit_counts: Vec<u32>,
+    /// Number of epsilon-greedy choices that explored because of epsilon.
+    pub exploratory_choices: usize,
+    /// Number of epsilon-greedy choices that were random only because every
+    /// action had the same Q-value.
+    pub forced_random_choices: usize,
+    /// Relative probability of each action, indexed like a Q-row, when
+    /// exploring. Uniform by default.
+    pub exploration_weights: Vec<f32>,
+    /// Whether `reward` standardizes rewards using `reward_stats` before
+    /// updating.
+    pub normalize_rewards: bool,
+    /// Statistics of every reward passed to `reward` while normalizing.
+    pub reward_stats: RunningStats,
+    /// Statistics of the TD-errors seen by each percept, before any clipping.
+    pub td_error_stats: Vec<RunningStats>,
+    /// Percept, action and reward of each step so far this episode, buffered
+    /// for Monte Carlo updates.
+    episode_buffer: Vec<(Percept, Action, f32)>,
+    /// Number of Monte Carlo returns averaged into each Q-value, indexed like
+    /// `q_matrix`.
+    pub return_counts: Vec<Vec<u32>>,
+    /// Transitions seen by `reward`, recorded only when set.
+    pub transition_model: Option<TransitionModel>,
+    rng: StdRng,
+}
+
+impl Default for Robot {
+    fn default() -> Self {
+        Robot::new(0.0)
+    }
+}
+
+impl Robot {
+    pub fn new(epsilon: f32) -> Self {
+        Self::with_rng(
+            epsilon,
+            StdRng::from_os_rng(),
+            Arc::new(FullPercept::default()),
+        )
+    }
+
+    /// Create a robot whose action selection is reproducible for a given seed.
+    pub fn with_seed(epsilon: f32, seed: u64) -> Self {
+        Self::with_rng(
+            epsilon,
+            StdRng::seed_from_u64(seed),
+            Arc::new(FullPercept::default()),
+        )
+    }
+
+    /// Create a robot with a row for every percept including diagonal
+    /// neighbors, for environments with `diagonal_sensing` on.
+    pub fn new_diagonal(epsilon: f32) -> Self {
+        let abstraction = FullPercept {
+            diagonal_sensing: true,
+            ..Default::default()
+        };
+        Self::with_rng(epsilon, StdRng::from_os_rng(), Arc::new(abstraction))
+    }
+
+    /// Create a seeded robot with one Q-matrix row per state of the given
+    /// abstraction.
+    pub fn with_abstraction(
+        epsilon: f32,
+        seed: u64,
+        abstraction: impl StateAbstraction + 'static,
+    ) -> Self {
+        Self::with_rng(epsilon, StdRng::seed_from_u64(seed), Arc::new(abstraction))
+    }
+
+    fn with_rng(epsilon: f32, rng: StdRng, abstraction: Arc<dyn StateAbstraction>) -> Self {
+        let number_of_possible_percepts = abstraction.num_states();
+        let number_of_actions = 5;
+        Robot {
+            previous_choice: None,
+            q_matrix: vec![vec![0.0; number_of_actions]; number_of_possible_percepts],
+            epsilon,
+            percept_map: generate_percept_map(abstraction.as_ref()),
+            abstraction,
+            selection_policy: SelectionPolicy::default(),
+            update_rule: UpdateRule::default(),
+            eta_schedule: EtaSchedule::default(),
+            tie_break: TieBreak::default(),
+            action_masking: false,
+            open_grid: true,
+            action_counts: vec![vec![0; number_of_actions]; number_of_possible_percepts],
+            visit_counts: vec![0; number_of_possible_percepts],
+            exploratory_choices: 0,
+            forced_random_choices: 0,
+            exploration_weights: vec![1.0; number_of_actions],
+            normalize_rewards: false,
+            reward_stats: RunningStats::default(),
+            td_error_stats: vec![RunningStats::default(); number_of_possible_percepts],
+            episode_buffer: vec![],
+            return_counts: vec![vec![0; number_of_actions]; number_of_possible_percepts],
+            transition_model: None,
+            rng,
+        }
+    }
+
+    /// A copy of the robot's random source as it is now. Restoring it with
+    /// `restore_rng` replays the same draws from this point on.
+    pub fn rng_snapshot(&self) -> StdRng {
+        self.rng.clone()
+    }
+
+    /// Put back a random source saved with `rng_snapshot`.
+    pub fn restore_rng(&mut self, state: StdRng) {
+        self.rng = state;
+    }
+
+    pub fn select_action(&mut self, p: &Percept) -> Action {
+        let out = match self.selection_policy {
+            SelectionPolicy::EpsilonGreedy => {
+                let r: f32 = self.rng.random_range(0.0..1.0);
+
+                let explore = self.epsilon > r;
+                if explore {
+                    self.exploratory_choices += 1;
+                } else if self.all_actions_same(p) {
+                    self.forced_random_choices += 1;
+                }
+                match explore || self.all_actions_same(p) {
+                    true => self.exploratory_action(p),
+                    false => self.seeded_max_action(p),
+                }
+            }
+            SelectionPolicy::Ucb1 { c } => self.ucb1_action(p, c),
+        };
+
+        let percept_index = self.percept_map[p];
+        self.action_counts[percept_index][usize::from(out.clone())] += 1;
+        self.visit_counts[percept_index] += 1;
+
+        self.previous_choice = Some((p.clone(), out.clone()));
+
+        out
+    }
+
+    /// Draw a random action according to `exploration_weights`, leaving out
+    /// masked actions.
+    fn exploratory_action(&mut self, p: &Percept) -> Action {
+        let allowed = self.allowed_actions(p);
+        let weights: Vec<f32> = self
+            .exploration_weights
+            .iter()
+            .zip(allowed)
+            .map(|(w, allowed)| if allowed { *w } else { 0.0 })
+            .collect();
+        let total: f32 = weights.iter().sum();
+        assert!(total > 0.0, "exploration weights must not all be zero");
+
+        let mut r: f32 = self.rng.random_range(0.0..total);
+        for (i, weight) in weights.iter().enumerate() {
+            if r < *weight {
+                return i.into();
+            }
+            r -= weight;
+        }
+
+        // Rounding can leave a sliver past the last weight; fall back to the
+        // last action that can actually be drawn.
+        let last = weights.iter().rposition(|w| *w > 0.0);
+        last.unwrap().into()
+    }
+
+    /// Which actions, indexed like a Q-row, `select_action` may choose. With
+    /// `action_masking` on, moves into a wall visible in the percept are
+    /// excluded; otherwise everything is allowed.
+    pub fn allowed_actions(&self, p: &Percept) -> [bool; 5] {
+        use LocationValue::Wall;
+        match self.action_masking {
+            true => [
+                p.north != Wall,
+                p.south != Wall,
+                p.east != Wall,
+                p.west != Wall,
+                true,
+            ],
+            false => [true; 5],
+        }
+    }
+
+    /// `values` with the entries of masked actions replaced by negative
+    /// infinity, so they can never be the largest.
+    fn mask_values(&self, p: &Percept, values: &[f32]) -> Vec<f32> {
+        values
+            .iter()
+            .zip(self.allowed_actions(p))
+            .map(|(v, allowed)| if allowed { *v } else { f32::NEG_INFINITY })
+            .collect()
+    }
+
+    /// Choose the best known action for the percept without exploring or
+    /// recording anything for the next update. Ties are always broken by
+    /// lowest action index, whatever `tie_break` says, so evaluation runs are
+    /// reproducible.
+    pub fn select_greedy_action(&mut self, p: &Percept) -> Action {
+        let values = self.mask_values(p, &self.q_matrix[self.percept_map[p]]);
+        argmax_first(&values).into()
+    }
+
+    /// The best action for the percept, breaking ties by lowest action index.
+    fn first_best_action(&self, p: &Percept) -> Action {
+        argmax_first(&self.q_matrix[self.percept_map[p]]).into()
+    }
+
+    /// The greedy action for each percept, computing rows with the state
+    /// abstraction rather than looking them up. Ties are broken by lowest
+    /// action index.
+    pub fn greedy_actions(&self, percepts: &[Percept]) -> Vec<Action> {
+        percepts
+            .iter()
+            .map(|p| argmax_first(&self.q_matrix[self.abstraction.abstract_index(p)]).into())
+            .collect()
+    }
+
+    /// Choose the action maximizing the UCB1 score for the given percept. With
+    /// `c == 0` this is plain greedy selection.
+    fn ucb1_action(&mut self, p: &Percept, c: f32) -> Action {
+        if c == 0.0 {
+            return self.seeded_max_action(p);
+        }
+
+        let percept_index = self.percept_map[p];
+        let actions = &self.q_matrix[percept_index];
+        let counts = &self.action_counts[percept_index];
+        let state_visits: u32 = counts.iter().sum();
+
+        let scores: Vec<f32> = actions
+            .iter()
+            .zip(counts)
+            .map(|(q, n)| match n {
+                0 => f32::INFINITY,
+                _ => q + c * ((state_visits as f32).ln() / *n as f32).sqrt(),
+            })
+            .collect();
+        let scores = self.mask_values(p, &scores);
+
+        argmax_random_tie(&scores, &mut self.rng).0.into()
+    }
+
+    pub fn all_actions_same(&self, p: &Percept) -> bool {
+        let percept_index = self.percept_map[p];
+        let actions = &self.q_matrix[percept_index];
+
+        actions
+            .iter()
+            .fold((actions[0], true), |acc, score| {
+                (*score, acc.1 && acc.0 == *score)
+            })
+            .1
+    }
+
+    /// Return the best allowed action for the percept and its Q-value, drawing
+    /// nothing. Ties go to picking up under `TieBreak::PreferPickup` and
+    /// otherwise to the lowest action index; `select_action` instead breaks
+    /// them with the robot's rng.
+    pub fn max_action_for_percept(&self, p: &Percept) -> (Action, f32) {
+        let (candidates, max_score) = self.best_candidates(p);
+        (candidates[0].into(), max_score)
+    }
+
+    /// Like `max_action_for_percept`, but breaking ties at random with the
+    /// robot's rng, which is only drawn from when there is more than one
+    /// candidate.
+    fn seeded_max_action(&mut self, p: &Percept) -> Action {
+        let (candidates, _) = self.best_candidates(p);
+        let choice_index = match candidates.len() {
+            1 => candidates[0],
+            n => candidates[self.rng.random_range(0..n)],
+        };
+        choice_index.into()
+    }
+
+    /// The allowed actions sharing the best Q-value for the percept, narrowed
+    /// down to picking up if `tie_break` prefers it, and that value.
+    fn best_candidates(&self, p: &Percept) -> (Vec<usize>, f32) {
+        let values = self.mask_values(p, &self.q_matrix[self.percept_map[p]]);
+        let (candidates, max_score) = max_candidates(&values);
+
+        let pickup = usize::from(Action::PickUpCan);
+        match self.tie_break {
+            TieBreak::PreferPickup if candidates.contains(&pickup) => (vec![pickup], max_score),
+            _ => (candidates, max_score),
+        }
+    }
+
+    /// Difference between the best and second-best Q-values for the percept.
+    /// Zero when the best value is tied.
+    pub fn action_gap(&self, p: &Percept) -> f32 {
+        let mut row = self.q_matrix[self.percept_map[p]].clone();
+        row.sort_by(|a, b| b.total_cmp(a));
+        row[0] - row[1]
+    }
+
+    /// The Q-value `max_action_for_percept` would report for the percept,
+    /// found without drawing randoms.
+    pub fn greedy_q(&self, p: &Percept) -> f32 {
+        let values = self.mask_values(p, &self.q_matrix[self.percept_map[p]]);
+        values.into_iter().fold(f32::NEG_INFINITY, f32::max)
+    }
+
+    /// Return the largest Q-value for the percept without drawing randoms.
+    pub fn max_q_for_percept(&self, p: &Percept) -> f32 {
+        let percept_index = self.percept_map[p];
+        self.q_matrix[percept_index]
+            .iter()
+            .fold(f32::NEG_INFINITY, |acc, q| acc.max(*q))
+    }
+
+    /// Expected Q-value of the percept when acting epsilon-greedily: the best
+    /// action with probability `1 - epsilon`, a uniform one otherwise.
+    pub fn expected_action_value(&self, p: &Percept) -> f32 {
+        let percept_index = self.percept_map[p];
+        let actions = &self.q_matrix[percept_index];
+        let mean = actions.iter().sum::<f32>() / actions.len() as f32;
+
+        (1.0 - self.epsilon) * self.max_q_for_percept(p) + self.epsilon * mean
+    }
+
+    /// The greedy policy on `env`'s grid as a Graphviz digraph. There is one
+    /// node per cell, labelled with its coordinates and contents, and one edge
+    /// from each open cell to where the greedy action taken there leads,
+    /// labelled with the action. Pickups and blocked moves are self-loops.
+    /// Ties resolve to the lowest action index.
+    pub fn policy_dot(&self, env: &Environment) -> String {
+        let dim = env.grid_dimension;
+        let node = |(x, y): (usize, usize)| format!("c{}_{}", x, y);
+
+        let mut out = String::from("digraph policy {\n");
+        for x in 0..dim {
+            for y in 0..dim {
+                out.push_str(&format!(
+                    "    {} [label=\"({}, {}) {}\"];\n",
+                    node((x, y)),
+                    x,
+                    y,
+                    env.grid[x][y]
+                ));
+            }
+        }
+
+        let mut at = env.clone();
+        for x in 0..dim {
+            for y in 0..dim {
+                if env.grid[x][y] == LocationValue::Wall {
+                    continue;
+                }
+                at.robot_coordinates = (x, y);
+                let a = self.first_best_action(&at.create_percept());
+                let next = at.destination(&a).unwrap_or((x, y));
+                out.push_str(&format!(
+                    "    {} -> {} [label=\"{}\"];\n",
+                    node((x, y)),
+                    node(next),
+                    a
+                ));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Render `env` before and after each of up to `steps` greedy steps. Ties
+    /// resolve to the lowest action index and nothing is learned. Stops early
+    /// if the episode ends.
+    pub fn greedy_trajectory_frames(&self, env: &mut Environment, steps: usize) -> Vec<String> {
+        let mut frames = vec![env.render()];
+        for _ in 0..steps {
+            if env.done {
+                break;
+            }
+            let a = self.first_best_action(&env.create_percept());
+            step_env(env, &a);
+            frames.push(env.render());
+        }
+        frames
+    }
+
+    /// Fraction of reachable percepts for which this robot and `other` would
+    /// choose a different greedy action. Ties resolve to the lowest action
+    /// index so the comparison is deterministic.
+    pub fn policy_disagreement(&self, other: &Robot) -> f32 {
+        let mut total = 0_usize;
+        let mut differing = 0_usize;
+
+        for (p, i) in self
+            .percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+        {
+            let j = other.percept_map[p];
+            total += 1;
+            if argmax_first(&self.q_matrix[*i]) != argmax_first(&other.q_matrix[j]) {
+                differing += 1;
+            }
+        }
+
+        differing as f32 / total as f32
+    }
+
+    /// The actions that are the greedy choice in at least one reachable
+    /// percept. Ties resolve to the lowest action index.
+    pub fn actions_used(&self) -> HashSet<Action> {
+        self.percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+            .map(|(_, i)| argmax_first(&self.q_matrix[*i]).into())
+            .collect()
+    }
+
+    /// FNV-1a hash of the greedy action in every reachable percept, taken in
+    /// row order with ties resolved to the lowest action index. Robots with
+    /// the same greedy policy have the same fingerprint, and the value is
+    /// stable across builds and Rust releases, so it can be stored.
+    pub fn policy_fingerprint(&self) -> u64 {
+        let mut rows: Vec<usize> = self
+            .percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+            .map(|(_, i)| *i)
+            .collect();
+        rows.sort_unstable();
+        rows.dedup();
+
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        rows.into_iter()
+            .flat_map(|i| {
+                let action = argmax_first(&self.q_matrix[i]) as u64;
+                [(i as u64).to_le_bytes(), action.to_le_bytes()]
+            })
+            .flatten()
+            .fold(FNV_OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// Average, over `trials`, of the fraction of reachable percepts whose
+    /// greedy action survives adding Gaussian noise with standard deviation
+    /// `noise_std` to every Q-value. Ties resolve to the lowest action index.
+    /// The noise is drawn from a copy of the robot's random source, so a
+    /// seeded robot gives the same answer each time.
+    pub fn robustness(&self, noise_std: f32, trials: usize) -> f32 {
+        assert!(trials > 0, "need at least one trial");
+        let mut rng = self.rng.clone();
+        let rows: Vec<usize> = self
+            .percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+            .map(|(_, i)| *i)
+            .collect();
+
+        let mut unchanged = 0_usize;
+        for _ in 0..trials {
+            for i in &rows {
+                let noisy: Vec<f32> = self.q_matrix[*i]
+                    .iter()
+                    .map(|q| q + noise_std * standard_normal(&mut rng))
+                    .collect();
+                if argmax_first(&noisy) == argmax_first(&self.q_matrix[*i]) {
+                    unchanged += 1;
+                }
+            }
+        }
+        unchanged as f32 / (trials * rows.len()) as f32
+    }
+
+    /// How much each sensor helps pin down the greedy action, in bits, ordered
+    /// `[current, north, south, east, west]`. A sensor's importance is the
+    /// entropy of the greedy action over reachable percepts that agree on every
+    /// other sensor, i.e. the entropy gained by marginalizing that sensor out.
+    pub fn sensor_importance(&self) -> [f32; 5] {
+        let percepts: Vec<&Percept> = self
+            .percept_map
+            .keys()
+            .filter(|p| p.is_reachable_on(self.open_grid))
+            .collect();
+        let mut importance = [0.0; 5];
+
+        for (field, value) in importance.iter_mut().enumerate() {
+            let mut groups: HashMap<Vec<LocationValue>, [usize; 5]> = HashMap::new();
+            for p in &percepts {
+                let mut key = vec![p.current, p.north, p.south, p.east, p.west];
+                key.remove(field);
+                let action = argmax_first(&self.q_matrix[self.percept_map[*p]]);
+                groups.entry(key).or_insert([0; 5])[action] += 1;
+            }
+
+            let entropy: f32 = groups
+                .values()
+                .map(|counts| {
+                    let group_size = counts.iter().sum::<usize>() as f32;
+                    let group_entropy: f32 = counts
+                        .iter()
+                        .filter(|c| **c > 0)
+                        .map(|c| {
+                            let prob = *c as f32 / group_size;
+                            -prob * prob.log2()
+                        })
+                        .sum();
+                    group_size * group_entropy
+                })
+                .sum();
+            *value = entropy / percepts.len() as f32;
+        }
+
+        importance
+    }
+
+    /// Observed frequency of each resulting percept after taking `a` in `p`,
+    /// or nothing if the transition model is off.
+    pub fn transition_probs(&self, p: &Percept, a: &Action) -> Vec<(Percept, f32)> {
+        self.transition_model
+            .as_ref()
+            .map_or(vec![], |model| model.transition_probs(p, a))
+    }
+
+    /// Largest and mean Bellman optimality residual `|Q(s,a) - (r + gamma *
+    /// E[max_a' Q(s',a')])|` over every percept and action in the transition
+    /// model, using its mean rewards and observed next-percept frequencies.
+    /// Both are zero if the model is off or empty.
+    pub fn bellman_residual(&self, gamma: f32) -> (f32, f32) {
+        let Some(model) = &self.transition_model else {
+            return (0.0, 0.0);
+        };
+        if model.seen.is_empty() {
+            return (0.0, 0.0);
+        }
+
+        let residuals: Vec<f32> = model
+            .seen
+            .iter()
+            .map(|(p, a)| {
+                let expected_next: f32 = model
+                    .transition_probs(p, a)
+                    .iter()
+                    .map(|(next, prob)| prob * self.max_q_for_percept(next))
+                    .sum();
+                let target = model.rewards[&(p.clone(), a.clone())].mean + gamma * expected_next;
+                (self.q_matrix[self.percept_map[p]][usize::from(a.clone())] - target).abs()
+            })
+            .collect();
+
+        let max = residuals.iter().fold(0.0, |acc: f32, r| acc.max(*r));
+        (max, residuals.iter().sum::<f32>() / residuals.len() as f32)
+    }
+
+    /// Give the oracle's choice in each reachable percept a head start of
+    /// `bonus`. Percepts only show the neighboring cells, so the oracle is
+    /// asked about a 3x3 grid holding just what the percept shows: it picks up
+    /// on a can, or steps towards a neighboring one. Percepts with nothing
+    /// collectible in sight are left alone.
+    pub fn warm_start_from_oracle(&mut self, bonus: f32) {
+        for (p, i) in self
+            .percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+        {
+            let mut local = Environment::new(3, 0, (1, 1));
+            local.set_cell((1, 1), p.current);
+            local.set_cell((2, 1), p.north);
+            local.set_cell((0, 1), p.south);
+            local.set_cell((1, 2), p.east);
+            local.set_cell((1, 0), p.west);
+
+            if let Some(a) = oracle_action(&local) {
+                self.q_matrix[*i][usize::from(a)] += bonus;
+            }
+        }
+    }
+
+    /// Fraction of `samples` states, each the starting percept of an
+    /// environment from `env_sampler`, where the greedy action is also best
+    /// under a one-step lookahead through the learned transition model: the
+    /// mean observed reward plus `gamma` times the expected best Q-value of the
+    /// resulting percept. Actions never tried in a state are left out of its
+    /// lookahead, and states with no tried actions, or no transition model at
+    /// all, are skipped.
+    pub fn stable_fraction(
+        &self,
+        gamma: f32,
+        samples: usize,
+        mut env_sampler: impl FnMut() -> Environment,
+    ) -> f32 {
+        let Some(model) = &self.transition_model else {
+            return 0.0;
+        };
+
+        let mut checked = 0_usize;
+        let mut stable = 0_usize;
+        for _ in 0..samples {
+            let p = env_sampler().create_percept();
+            let lookahead: Vec<(usize, f32)> = all_actions()
+                .into_iter()
+                .filter_map(|a| {
+                    let key = (p.clone(), a.clone());
+                    let mean_reward = model.rewards.get(&key)?.mean;
+                    let next_value: f32 = model
+                        .transition_probs(&p, &a)
+                        .iter()
+                        .map(|(next, prob)| prob * self.max_q_for_percept(next))
+                        .sum();
+                    Some((usize::from(a), mean_reward + gamma * next_value))
+                })
+                .collect();
+            if lookahead.is_empty() {
+                continue;
+            }
+
+            let best = lookahead
+                .iter()
+                .fold(f32::NEG_INFINITY, |acc, (_, value)| acc.max(*value));
+            let greedy = argmax_first(&self.q_matrix[self.percept_map[&p]]);
+            checked += 1;
+            if lookahead
+                .iter()
+                .any(|(i, value)| *i == greedy && *value == best)
+            {
+                stable += 1;
+            }
+        }
+
+        match checked {
+            0 => 0.0,
+            _ => stable as f32 / checked as f32,
+        }
+    }
+
+    /// The kind of the first non-finite Q-value, in row order, or `None` if
+    /// every Q-value is finite.
+    pub fn divergence_check(&self) -> Option<DivergenceKind> {
+        self.q_matrix
+            .iter()
+            .flatten()
+            .find(|q| !q.is_finite())
+            .map(|q| match q {
+                _ if q.is_nan() => DivergenceKind::NaN,
+                _ if *q > 0.0 => DivergenceKind::PositiveInfinity,
+                _ => DivergenceKind::NegativeInfinity,
+            })
+    }
+
+    /// Variance of the TD-errors seen so far by each percept, indexed like the
+    /// Q-matrix rows. High values mark states whose estimates are still
+    /// moving around.
+    pub fn state_uncertainty(&self) -> Vec<f32> {
+        self.td_error_stats.iter().map(|s| s.variance()).collect()
+    }
+
+    /// Compress the Q-matrix to 8 bits per value, scaled between the matrix's
+    /// smallest and largest entries.
+    pub fn quantize(&self) -> QuantizedPolicy {
+        let (min, max) = self
+            .q_matrix
+            .iter()
+            .flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), q| {
+                (lo.min(*q), hi.max(*q))
+            });
+        let scale = (max - min) / 255.0;
+
+        let values = self
+            .q_matrix
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|q| match scale > 0.0 {
+                        true => (((q - min) / scale).round() - 128.0) as i8,
+                        false => -128,
+                    })
+                    .collect()
+            })
+            .collect();
+
+        QuantizedPolicy {
+            values,
+            scale,
+            offset: min,
+        }
+    }
+
+    /// Write the Q-matrix as a little-endian float32 `.npy` file that
+    /// `numpy.load` reads as a `(percepts, actions)` array.
+    pub fn save_npy(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let rows = self.q_matrix.len();
+        let columns = self.q_matrix.first().map_or(0, |row| row.len());
+
+        let mut header = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+            rows, columns
+        );
+        // Magic (6) + version (2) + header length (2) + header + newline must
+        // be a multiple of 64 bytes.
+        let unpadded = 10 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+        header.push('\n');
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(10 + header.len() + rows * columns * 4);
+        bytes.extend_from_slice(b"\x93NUMPY");
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        for value in self.q_matrix.iter().flatten() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        File::create(path)?.write_all(&bytes)
+    }
+
+    /// Reset the Q-values of every percept that can't actually occur to zero.
+    pub fn zero_unreachable(&mut self) {
+        let reachable_rows: HashSet<usize> = self
+            .percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+            .map(|(_, i)| *i)
+            .collect();
+        for (i, row) in self.q_matrix.iter_mut().enumerate() {
+            if !reachable_rows.contains(&i) {
+                row.iter_mut().for_each(|q| *q = 0.0);
+            }
+        }
+    }
+
+    /// Write the visit count of every reachable percept as CSV, using the same
+    /// percept columns as the weights export.
+    pub fn visit_counts_csv(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut percepts: Vec<(&Percept, &usize)> = self
+            .percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+            .collect();
+        percepts.sort_by_key(|(_, i)| **i);
+
+        let mut file = File::create(path)?;
+        writeln!(file, "Current,North,South,East,West,Visits")?;
+        for (p, i) in percepts {
+            write_csv_row(
+                &mut file,
+                &[
+                    p.current.to_string(),
+                    p.north.to_string(),
+                    p.south.to_string(),
+                    p.east.to_string(),
+                    p.west.to_string(),
+                    self.visit_counts[*i].to_string(),
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the Q-values of every reachable percept as CSV, one row per
+    /// percept with a column per action.
+    pub fn weights_csv(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut percepts: Vec<(&Percept, &usize)> = self
+            .percept_map
+            .iter()
+            .filter(|(p, _)| p.is_reachable_on(self.open_grid))
+            .collect();
+        percepts.sort_by_key(|(_, i)| **i);
+
+        let mut file = File::create(path)?;
+        let mut header: Vec<String> = ["Current", "North", "South", "East", "West"]
+            .iter()
+            .map(|h| h.to_string())
+            .collect();
+        header.extend(all_actions().iter().map(|a| a.to_string()));
+        write_csv_row(&mut file, &header)?;
+
+        for (p, i) in percepts {
+            let mut fields = vec![
+                p.current.to_string(),
+                p.north.to_string(),
+                p.south.to_string(),
+                p.east.to_string(),
+                p.west.to_string(),
+            ];
+            fields.extend(self.q_matrix[*i].iter().map(|q| csv_float(*q)));
+            write_csv_row(&mut file, &fields)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the reward to the running statistics and return it standardized
+    /// against them. Returns 0 until there is any spread to divide by.
+    fn normalize_reward(&mut self, reward_amount: f32) -> f32 {
+        self.reward_stats.push(reward_amount);
+        match self.reward_stats.std_dev() {
+            0.0 => 0.0,
+            std_dev => (reward_amount - self.reward_stats.mean) / std_dev,
+        }
+    }
+
+    /// Learn from the reward for the last selected action. When `done`, the
+    /// transition ended the episode, so the target is the reward alone with
+    /// nothing bootstrapped from `resulting_percept`.
+    pub fn reward(
+        &mut self,
+        reward_amount: f32,
+        eta: f32,
+        gamma: f32,
+        resulting_percept: &Percept,
+        done: bool,
+    ) {
+        let reward_amount = match self.normalize_rewards {
+            true => self.normalize_reward(reward_amount),
+            false => reward_amount,
+        };
+
+        if matches!(self.update_rule, UpdateRule::DynaQ { .. }) && self.transition_model.is_none() {
+            self.transition_model = Some(TransitionModel::default());
+        }
+        if let (Some(model), Some((p, a))) = (&mut self.transition_model, &self.previous_choice) {
+            model.record(p, a, reward_amount, resulting_percept);
+        }
+
+        if self.update_rule == UpdateRule::MonteCarlo {
+            if let Some((p, a)) = &self.previous_choice {
+                self.episode_buffer
+                    .push((p.clone(), a.clone(), reward_amount));
+            }
+            return;
+        }
+
+        if let Some((p, a)) = &self.previous_choice {
+            // TODO fix this unwrap nightmare
+            // TODO Add epsilon and deeper update logic
+            let percept_index = self.percept_map[p];
+            let action_index = usize::from(a.clone());
+            let current_q = self.q_matrix[percept_index][action_index];
+
+            let next_value = match (done, &self.update_rule) {
+                (true, _) => 0.0,
+                (false, UpdateRule::ExpectedSarsa) => self.expected_action_value(resulting_percept),
+                (false, _) => self.max_q_for_percept(resulting_percept),
+            };
+            let mut td_error = reward_amount + gamma * next_value - current_q;
+            self.td_error_stats[percept_index].push(td_error);
+            if let UpdateRule::HuberClipped { delta } = self.update_rule {
+                td_error = td_error.clamp(-delta, delta);
+            }
+
+            let eta = self.effective_eta(p, a, eta);
+            self.q_matrix[percept_index][action_index] = current_q + eta * td_error;
+        }
+
+        if let UpdateRule::DynaQ { planning_steps } = self.update_rule {
+            self.plan(planning_steps, eta, gamma);
+        }
+    }
+
+    /// The learning rate an update of `a` in `p` would use under
+    /// `eta_schedule`, given the `eta` passed to `reward`.
+    pub fn effective_eta(&self, p: &Percept, a: &Action, eta: f32) -> f32 {
+        match self.eta_schedule {
+            EtaSchedule::Constant => eta,
+            EtaSchedule::VisitCount => {
+                let visits = self.action_counts[self.percept_map[p]][usize::from(a.clone())];
+                1.0 / (1.0 + visits as f32)
+            }
+        }
+    }
+
+    /// Apply `steps` Q-learning updates to transitions sampled from the
+    /// transition model, each with the learning rate `effective_eta` gives.
+    fn plan(&mut self, steps: usize, eta: f32, gamma: f32) {
+        let Some(model) = &self.transition_model else {
+            return;
+        };
+
+        for _ in 0..steps {
+            let Some((p, a, reward_amount, next)) = model.sample(&mut self.rng) else {
+                return;
+            };
+            let percept_index = self.percept_map[p];
+            let action_index = usize::from(a.clone());
+            let next_value = self.q_matrix[self.percept_map[&next]]
+                .iter()
+                .fold(f32::NEG_INFINITY, |acc, q| acc.max(*q));
+            let eta = self.effective_eta(p, a, eta);
+
+            let q = &mut self.q_matrix[percept_index][action_index];
+            *q += eta * (reward_amount + gamma * next_value - *q);
+        }
+    }
+
+    /// Finish an episode. Under `UpdateRule::MonteCarlo` this applies the
+    /// buffered first-visit returns, discounted by `gamma`; other rules have
+    /// nothing to do.
+    pub fn end_episode(&mut self, gamma: f32) {
+        let buffer = std::mem::take(&mut self.episode_buffer);
+
+        let mut returns = vec![0.0; buffer.len()];
+        let mut following = 0.0;
+        for (t, (_, _, reward_amount)) in buffer.iter().enumerate().rev() {
+            following = reward_amount + gamma * following;
+            returns[t] = following;
+        }
+
+        let mut visited: HashSet<(usize, usize)> = HashSet::new();
+        for ((p, a, _), episode_return) in buffer.iter().zip(returns) {
+            let key = (self.percept_map[p], usize::from(a.clone()));
+            if !visited.insert(key) {
+                continue;
+            }
+
+            let (percept_index, action_index) = key;
+            self.return_counts[percept_index][action_index] += 1;
+            let n = self.return_counts[percept_index][action_index] as f32;
+            let q = &mut self.q_matrix[percept_index][action_index];
+            *q += (episode_return - *q) / n;
+        }
+    }
+}
+
+/// A Q-learning agent that approximates each action's value as a linear
+/// function of `Percept::features`, so learning is shared between percepts
+/// with sensor readings in common.
+pub struct LinearRobot {
+    previous_choice: Option<(Percept, Action)>,
+    /// One weight vector per action, indexed like `Action`.
+    pub weights: Vec<[f32; 15]>,
+    pub epsilon: f32,
+    rng: StdRng,
+}
+
+impl LinearRobot {
+    pub fn new(epsilon: f32) -> Self {
+        Self::with_rng(epsilon, StdRng::from_os_rng())
+    }
+
+    /// Create a robot whose action selection is reproducible for a given seed.
+    pub fn with_seed(epsilon: f32, seed: u64) -> Self {
+        Self::with_rng(epsilon, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(epsilon: f32, rng: StdRng) -> Self {
+        LinearRobot {
+            previous_choice: None,
+            weights: vec![[0.0; 15]; all_actions().len()],
+            epsilon,
+            rng,
+        }
+    }
+
+    /// Estimated value of each action for the given percept.
+    pub fn q_values(&self, p: &Percept) -> Vec<f32> {
+        let features = p.features();
+        self.weights
+            .iter()
+            .map(|w| w.iter().zip(features.iter()).map(|(w, f)| w * f).sum())
+            .collect()
+    }
+
+    /// Choose an action epsilon-greedily, breaking ties at random.
+    pub fn select_action(&mut self, p: &Percept) -> Action {
+        let r: f32 = self.rng.random_range(0.0..1.0);
+        let out: Action = match self.epsilon > r {
+            true => self.rng.random_range(0..self.weights.len()).into(),
+            false => argmax_random_tie(&self.q_values(p), &mut self.rng).0.into(),
+        };
+
+        self.previous_choice = Some((p.clone(), out.clone()));
+        out
+    }
+
+    /// Take a gradient step on the TD-error of the previous choice, moving
+    /// only the chosen action's weights. Nothing is bootstrapped past a
+    /// transition that was `done`.
+    pub fn reward(
+        &mut self,
+        reward_amount: f32,
+        eta: f32,
+        gamma: f32,
+        resulting_percept: &Percept,
+        done: bool,
+    ) {
+        if let Some((p, a)) = &self.previous_choice {
+            let action_index = usize::from(a.clone());
+            let current_q = self.q_values(p)[action_index];
+            let next_value = match done {
+                true => 0.0,
+                false => self
+                    .q_values(resulting_percept)
+                    .into_iter()
+                    .fold(f32::NEG_INFINITY, f32::max),
+            };
+            let td_error = reward_amount + gamma * next_value - current_q;
+
+            for (w, f) in self.weights[action_index].iter_mut().zip(p.features()) {
+                *w += eta * td_error * f;
+            }
+        }
+    }
+}
+
+/// Common interface of the learning agents, so harness code can drive any of
+/// them.
+pub trait Agent {
+    /// Choose an action for the percept, remembering it for the next `reward`.
+    fn select_action(&mut self, p: &Percept) -> Action;
+    /// Learn from the reward for the last selected action, which ended the
+    /// episode if `done`.
+    fn reward(
+        &mut self,
+        reward_amount: f32,
+        eta: f32,
+        gamma: f32,
+        resulting_percept: &Percept,
+        done: bool,
+    );
+    /// Called once each episode is over.
+    fn end_episode(&mut self, _gamma: f32) {}
+}
+
+impl Agent for Robot {
+    fn select_action(&mut self, p: &Percept) -> Action {
+        Robot::select_action(self, p)
+    }
+
+    fn reward(
+        &mut self,
+        reward_amount: f32,
+        eta: f32,
+        gamma: f32,
+        resulting_percept: &Percept,
+        done: bool,
+    ) {
+        Robot::reward(self, reward_amount, eta, gamma, resulting_percept, done)
+    }
+
+    fn end_episode(&mut self, gamma: f32) {
+        Robot::end_episode(self, gamma)
+    }
+}
+
+impl Agent for LinearRobot {
+    fn select_action(&mut self, p: &Percept) -> Action {
+        LinearRobot::select_action(self, p)
+    }
+
+    fn reward(
+        &mut self,
+        reward_amount: f32,
+        eta: f32,
+        gamma: f32,
+        resulting_percept: &Percept,
+        done: bool,
+    ) {
+        LinearRobot::reward(self, reward_amount, eta, gamma, resulting_percept, done)
+    }
+}
+
+/// Result of running two agents on the same grids with `compare_agents`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComparisonReport {
+    /// Episode reward of agent `a` and agent `b` on each grid.
+    pub outcomes: Vec<(f32, f32)>,
+    /// Mean episode reward of `a` minus that of `b`.
+    pub reward_difference: f32,
+    /// Fraction of grids on which `a` earned strictly more than `b`.
+    pub win_rate: f32,
+    /// Number of grids on which both agents earned the same reward.
+    pub ties: usize,
+}
+
+/// Run each agent for up to `steps` steps on its own copy of every grid,
+/// choosing actions with `select_action` but never calling `reward`, and
+/// compare their episode rewards. Q-values are left alone, but whatever
+/// `select_action` records still changes: for a `Robot` that is its random
+/// source and its visit, action and exploration counts, which in turn affect
+/// `EtaSchedule::VisitCount` in later training. Compare clones to avoid that.
+pub fn compare_agents(
+    a: &mut dyn Agent,
+    b: &mut dyn Agent,
+    grids: &[Environment],
+    steps: usize,
+) -> ComparisonReport {
+    let run = |agent: &mut dyn Agent, grid: &Environment| {
+        let mut environment = grid.clone();
+        let mut episode_reward = 0.0;
+        for _ in 0..steps {
+            let action = agent.select_action(&environment.create_percept());
+            let (reward_amount, _, done) = step_env(&mut environment, &action);
+            episode_reward += reward_amount;
+            if done {
+                break;
+            }
+        }
+        episode_reward
+    };
+
+    let outcomes: Vec<(f32, f32)> = grids.iter().map(|g| (run(a, g), run(b, g))).collect();
+    let n = outcomes.len() as f32;
+
+    ComparisonReport {
+        reward_difference: outcomes.iter().map(|(ra, rb)| ra - rb).sum::<f32>() / n,
+        win_rate: outcomes.iter().filter(|(ra, rb)| ra > rb).count() as f32 / n,
+        ties: outcomes.iter().filter(|(ra, rb)| ra == rb).count(),
+        outcomes,
+    }
+}
+
+/// One episode of experience from `collect_trajectories`: each step's percept,
+/// the action taken, the reward earned and the percept that followed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trajectory {
+    pub steps: Vec<(Percept, Action, f32, Percept)>,
+}
+
+impl Trajectory {
+    /// Sum of the rewards earned over the trajectory.
+    pub fn total_reward(&self) -> f32 {
+        self.steps.iter().map(|(_, _, reward, _)| reward).sum()
+    }
+}
+
+/// Run the agent for up to `cap` steps on its own copy of every grid, choosing
+/// actions with `select_action` but never calling `reward`, and record each
+/// episode as a `Trajectory`. An episode stops early once the environment is
+/// done. As with `compare_agents`, a `Robot`'s Q-values are untouched but its
+/// selection counters and random source move on.
+pub fn collect_trajectories(
+    agent: &mut dyn Agent,
+    grids: &[Environment],
+    cap: usize,
+) -> Vec<Trajectory> {
+    grids
+        .iter()
+        .map(|grid| {
+            let mut environment = grid.clone();
+            let mut steps = vec![];
+            let mut percept = environment.create_percept();
+            for _ in 0..cap {
+                let action = agent.select_action(&percept);
+                let (reward_amount, next_percept, done) = step_env(&mut environment, &action);
+                steps.push((percept, action, reward_amount, next_percept.clone()));
+                percept = next_percept;
+                if done {
+                    break;
+                }
+            }
+            Trajectory { steps }
+        })
+        .collect()
+}
+
+#[test]
+fn test_environment_creation() {
+    let mut env = Environment {
+        grid_dimension: 10,
+        initial_number_of_cans: 20,
+        ..Default::default()
+    };
+    env.reset();
+
+    assert_eq!(env.initial_number_of_cans, env.count_cans());
+}
+
+#[test]
+fn test_percept_creation() {
+    use LocationValue::*;
+    let mut env = Environment::new(3, 0, (0, 0));
+
+    env.set_cell((1, 0), Can);
+
+    let mut out_p = env.create_percept();
+    assert_eq!(out_p.south, Wall);
+    assert_eq!(out_p.west, Wall);
+    assert_eq!(out_p.current, Empty);
+    assert_eq!(out_p.north, Can);
+
+    env.robot_coordinates = (2, 2);
+
+    out_p = env.create_percept();
+
+    assert_eq!(out_p.north, Wall);
+    assert_eq!(out_p.east, Wall);
+    assert_eq!(out_p.current, Empty);
+    assert_eq!(out_p.south, Empty);
+}
+
+#[test]
+fn test_percept_map_creation() {
+    let map = generate_percept_map(&FullPercept::default());
+    assert_eq!(map.len(), 4_usize.pow(5));
+    assert!(map.iter().all(|(p, i)| p.index() == *i));
+}
+
+#[test]
+fn test_ucb1_selection() {
+    use LocationValue::*;
+    let p = Percept {
+        current: Empty,
+        north: Empty,
+        south: Wall,
+        east: Empty,
+        west: Wall,
+        ..Default::default()
+    };
+
+    let mut rob = Robot::new(0.0);
+    let i = rob.percept_map[&p];
+    rob.q_matrix[i] = vec![5.0, 0.0, 0.0, 0.0, 0.0];
+    rob.action_counts[i] = vec![10, 10, 10, 10, 0];
+
+    rob.selection_policy = SelectionPolicy::Ucb1 { c: 100.0 };
+    assert!(rob.select_action(&p) == Action::PickUpCan);
+
+    rob.selection_policy = SelectionPolicy::Ucb1 { c: 0.0 };
+    for _ in 0..20 {
+        assert!(rob.select_action(&p) == Action::MoveNorth);
+    }
+}
+
+#[test]
+fn test_policy_disagreement() {
+    let mut rob = Robot::new(0.1);
+    for (i, row) in rob.q_matrix.iter_mut().enumerate() {
+        row[i % 5] = 1.0;
+    }
+    assert_eq!(rob.policy_disagreement(&rob), 0.0);
+
+    let mut shuffled = Robot::new(0.1);
+    for (i, row) in shuffled.q_matrix.iter_mut().enumerate() {
+        row[(i + 1) % 5] = 1.0;
+    }
+    assert!(rob.policy_disagreement(&shuffled) > 0.0);
+}
+
+#[test]
+fn test_start_positions() {
+    let env = Environment::new_randomized_with_start(5, 3, StartPosition::Corner);
+    assert_eq!(env.robot_coordinates, (0, 0));
+
+    let env = Environment::new_randomized_with_start(5, 3, StartPosition::Center);
+    assert_eq!(env.robot_coordinates, (2, 2));
+
+    let env = Environment::new_randomized_with_start(5, 3, StartPosition::Fixed(1, 4));
+    assert_eq!(env.robot_coordinates, (1, 4));
+}
+
+#[test]
+fn test_replay() {
+    use Action::*;
+    let mut env = Environment::new(3, 1, (0, 0));
+    env.set_cell((1, 0), LocationValue::Can);
+
+    let trace = env.replay(&[MoveSouth, MoveNorth, PickUpCan, PickUpCan, MoveEast]);
+
+    assert_eq!(
+        trace,
+        vec![
+            (-5.0, (0, 0)),
+            (0.0, (1, 0)),
+            (10.0, (1, 0)),
+            (-1.0, (1, 0)),
+            (0.0, (1, 1)),
+        ]
+    );
+    assert_eq!(env.crash_count, 1);
+}
+
+#[test]
+fn test_seeded_selection_is_reproducible() {
+    let mut env = Environment::new(5, 0, (2, 2));
+    env.set_cell((3, 2), LocationValue::Can);
+    let percepts: Vec<Percept> = all_actions()
+        .iter()
+        .map(|a| {
+            env.transition_state(a);
+            env.create_percept()
+        })
+        .collect();
+
+    let mut first = Robot::with_seed(0.3, 7);
+    let mut second = Robot::with_seed(0.3, 7);
+    for rob in [&mut first, &mut second] {
+        // Leave every row with a two-way tie so greedy choices hit the rng.
+        for row in rob.q_matrix.iter_mut() {
+            row[1] = 1.0;
+            row[3] = 1.0;
+        }
+    }
+
+    for _ in 0..50 {
+        for p in &percepts {
+            assert_eq!(first.select_action(p), second.select_action(p));
+            first.reward(0.5, 0.2, 0.9, p, false);
+            second.reward(0.5, 0.2, 0.9, p, false);
+        }
+    }
+}
+
+#[test]
+fn test_expected_sarsa_update() {
+    use LocationValue::*;
+    let p = Percept {
+        current: Can,
+        north: Empty,
+        south: Wall,
+        east: Empty,
+        west: Empty,
+        ..Default::default()
+    };
+    let next = Percept {
+        current: Empty,
+        north: Empty,
+        south: Empty,
+        east: Empty,
+        west: Empty,
+        ..Default::default()
+    };
+
+    let mut rob = Robot::with_seed(0.2, 1);
+    rob.update_rule = UpdateRule::ExpectedSarsa;
+    let next_index = rob.percept_map[&next];
+    rob.q_matrix[next_index] = vec![4.0, 2.0, 0.0, -1.0, 0.0];
+
+    // (1 - 0.2) * 4.0 + 0.2 * (5.0 / 5.0)
+    let expected = 3.4;
+    assert!((rob.expected_action_value(&next) - expected).abs() < 1e-6);
+
+    rob.select_action(&p);
+    let (pi, ai) = {
+        let (chosen_p, chosen_a) = rob.previous_choice.clone().unwrap();
+        (rob.percept_map[&chosen_p], usize::from(chosen_a))
+    };
+    rob.reward(1.0, 0.5, 0.9, &next, false);
+
+    let target = 0.5 * (1.0 + 0.9 * expected);
+    assert!((rob.q_matrix[pi][ai] - target).abs() < 1e-6);
+}
+
+#[test]
+fn test_pit_edges() {
+    let mut env = Environment::new(3, 0, (0, 1));
+    env.edge_behavior = EdgeBehavior::Pit { penalty: -50.0 };
+
+    assert_eq!(env.calculate_reward(&Action::MoveNorth), 0.0);
+    assert!(!env.done);
+
+    assert_eq!(env.calculate_reward(&Action::MoveSouth), -50.0);
+    assert!(env.done);
+    assert_eq!(env.crash_count, 0);
+}
+
+#[test]
+fn test_save_npy() {
+    let mut rob = Robot::new(0.1);
+    rob.q_matrix[0][1] = 1.5;
+    let path = std::env::temp_dir().join("rl_agent_test_save_npy.npy");
+    rob.save_npy(&path).unwrap();
+
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&bytes[..6], b"\x93NUMPY");
+    let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+    assert_eq!((10 + header_len) % 64, 0);
+
+    let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+    assert!(header.contains("'descr': '<f4'"));
+    assert!(header.contains("'shape': (1024, 5)"));
+    assert!(header.ends_with('\n'));
+
+    let data = &bytes[10 + header_len..];
+    assert_eq!(data.len(), 1024 * 5 * 4);
+    assert_eq!(f32::from_le_bytes(data[4..8].try_into().unwrap()), 1.5);
+}
+
+#[test]
+fn test_cylinder_wrapping() {
+    use LocationValue::*;
+    let mut env = Environment::new(3, 0, (2, 0));
+    env.wrap_y = true;
+    env.set_cell((2, 2), Can);
+
+    let p = env.create_percept();
+    assert_eq!(p.west, Can);
+    assert_eq!(p.north, Wall);
+
+    assert_eq!(env.calculate_reward(&Action::MoveWest), 0.0);
+    env.transition_state(&Action::MoveWest);
+    assert_eq!(env.robot_coordinates, (2, 2));
+
+    assert_eq!(env.calculate_reward(&Action::MoveNorth), -5.0);
+    env.transition_state(&Action::MoveNorth);
+    assert_eq!(env.robot_coordinates, (2, 2));
+    assert_eq!(env.crash_count, 1);
+}
+
+#[test]
+fn test_action_keys() {
+    assert_eq!(Action::from_key("n"), Some(Action::MoveNorth));
+    assert_eq!(Action::from_key("S"), Some(Action::MoveSouth));
+    assert_eq!(Action::from_key("e\n"), Some(Action::MoveEast));
+    assert_eq!(Action::from_key("w"), Some(Action::MoveWest));
+    assert_eq!(Action::from_key("p"), Some(Action::PickUpCan));
+    assert_eq!(Action::from_key("x"), None);
+    assert_eq!(Action::from_key("north"), None);
+    assert_eq!(Action::from_key(""), None);
+}
+
+#[test]
+fn test_render() {
+    let mut env = Environment::new(2, 1, (0, 1));
+    env.set_cell((1, 0), LocationValue::Can);
+    assert_eq!(env.render(), "C _\n_ R");
+}
+
+#[test]
+fn test_reward_breakdown() {
+    let mut env = Environment::new(3, 0, (0, 0));
+    env.reward_config.move_penalty = -0.5;
+
+    let crash = env.reward_breakdown(&Action::MoveSouth);
+    assert_eq!(
+        crash,
+        RewardBreakdown {
+            crash: -5.0,
+            ..Default::default()
+        }
+    );
+    assert_eq!(crash.total(), env.calculate_reward(&Action::MoveSouth));
+
+    let step = env.reward_breakdown(&Action::MoveNorth);
+    assert_eq!(step.move_penalty, -0.5);
+    assert_eq!(step.total(), -0.5);
+
+    env.set_cell((0, 0), LocationValue::Can);
+    assert_eq!(env.reward_breakdown(&Action::PickUpCan).can_pickup, 10.0);
+}
+
+#[test]
+fn test_terminate_on_wasted_pickup() {
+    let mut env = Environment::new(3, 1, (1, 1));
+    env.terminate_on_wasted_pickup = true;
+    env.set_cell((1, 1), LocationValue::Can);
+
+    let (reward_amount, _, done) = step_env(&mut env, &Action::PickUpCan);
+    assert_eq!(reward_amount, 10.0);
+    assert!(!done);
+
+    let (reward_amount, _, done) = step_env(&mut env, &Action::PickUpCan);
+    assert_eq!(reward_amount, -1.0);
+    assert!(done);
+}
+
+#[test]
+fn test_learning_auc() {
+    let records: Vec<EpisodeRecord> = (0..5)
+        .map(|i| EpisodeRecord {
+            episode_id: i,
+            episode_reward: i as f32,
+            ..Default::default()
+        })
+        .collect();
+
+    // Trapezoids under 0, 1, 2, 3, 4 cover 8.0, spread over 5 episodes.
+    assert_eq!(learning_auc(&records), 1.6);
+    assert_eq!(learning_auc(&[]), 0.0);
+}
+
+#[test]
+fn test_visit_counts() {
+    use LocationValue::*;
+    let corner = Percept {
+        current: Empty,
+        north: Empty,
+        south: Wall,
+        east: Empty,
+        west: Wall,
+        ..Default::default()
+    };
+    let on_can = Percept {
+        current: Can,
+        north: Empty,
+        south: Empty,
+        east: Empty,
+        west: Empty,
+        ..Default::default()
+    };
+
+    let mut rob = Robot::new(0.1);
+    for p in [&corner, &on_can, &corner, &corner] {
+        rob.select_action(p);
+    }
+
+    assert_eq!(rob.visit_counts[rob.percept_map[&corner]], 3);
+    assert_eq!(rob.visit_counts[rob.percept_map[&on_can]], 1);
+    assert_eq!(rob.visit_counts.iter().sum::<u32>(), 4);
+
+    let path = std::env::temp_dir().join("rl_agent_test_visit_counts.csv");
+    rob.visit_counts_csv(&path).unwrap();
+    let csv = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // 3 standable values * 15 north/south pairs * 15 east/west pairs.
+    assert_eq!(csv.lines().count(), 676);
+    assert!(csv.lines().any(|line| line == "E,E,W,E,W,3"));
+}
+
+#[test]
+fn test_zero_unreachable() {
+    let mut rob = Robot::new(0.1);
+    for row in rob.q_matrix.iter_mut() {
+        row.iter_mut().for_each(|q| *q = 1.0);
+    }
+
+    rob.zero_unreachable();
+
+    for (p, i) in rob.percept_map.iter() {
+        let expected = if p.is_reachable() { 1.0 } else { 0.0 };
+        assert!(rob.q_matrix[*i].iter().all(|q| *q == expected));
+    }
+}
+
+#[test]
+fn test_custom_generator() {
+    let env = Environment::new_with_generator(4, 4, |dimension, _| {
+        let mut grid = vec![vec![LocationValue::Empty; dimension]; dimension];
+        grid[0] = vec![LocationValue::Can; dimension];
+        grid
+    });
+
+    assert_eq!(env.count_cans(), 4);
+    assert!(env.grid[0].iter().all(|space| *space == LocationValue::Can));
+    assert!(
+        env.grid[1..]
+            .iter()
+            .flatten()
+            .all(|space| *space == LocationValue::Empty)
+    );
+}
+
+#[test]
+fn test_train_stops_on_nan() {
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(10),
+        n_episodes: 3,
+        m_steps: 20,
+        ..Default::default()
+    };
+
+    let mut rob = Robot::with_seed(0.1, 3);
+    assert_eq!(train(&mut rob, &params).unwrap().len(), 3);
+
+    let mut rob = Robot::with_seed(0.1, 3);
+    let nan_params = TrainParams {
+        eta: f32::NAN,
+        ..params.clone()
+    };
+    assert_eq!(
+        train(&mut rob, &nan_params).unwrap_err(),
+        TrainError::NonFiniteQ { episode_id: 0 }
+    );
+
+    // Monte Carlo only writes Q-values once the episode is over.
+    let mut rob = Robot::with_seed(0.1, 3);
+    rob.update_rule = UpdateRule::MonteCarlo;
+    let nan_params = TrainParams {
+        gamma: f32::NAN,
+        ..params.clone()
+    };
+    assert_eq!(
+        train(&mut rob, &nan_params).unwrap_err(),
+        TrainError::NonFiniteQ { episode_id: 0 }
+    );
+
+    // Dyna-Q planning can replay a transition into a row other than the one
+    // just updated.
+    let mut rob = Robot::with_seed(0.1, 3);
+    rob.update_rule = UpdateRule::DynaQ { planning_steps: 5 };
+    rob.transition_model = Some(TransitionModel::default());
+    let p = Percept::default();
+    rob.transition_model
+        .as_mut()
+        .unwrap()
+        .record(&p, &Action::PickUpCan, f32::INFINITY, &p);
+    assert_eq!(
+        train(&mut rob, &params).unwrap_err(),
+        TrainError::NonFiniteQ { episode_id: 0 }
+    );
+}
+
+#[test]
+fn test_gold_pickup() {
+    let mut env = Environment::new(3, 0, (1, 1));
+    env.set_cell((1, 1), LocationValue::Gold);
+    env.set_cell((2, 1), LocationValue::Can);
+
+    let p = env.create_percept();
+    assert_eq!(p.current, LocationValue::Gold);
+    assert_eq!(p.north, LocationValue::Can);
+
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), 25.0);
+    env.transition_state(&Action::PickUpCan);
+    assert_eq!(env.grid[1][1], LocationValue::Empty);
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), -1.0);
+
+    let rob = Robot::new(0.1);
+    assert_eq!(rob.q_matrix.len(), 1024);
+    assert_eq!(rob.percept_map[&p], p.index());
+}
+
+#[test]
+fn test_rollout_lengths() {
+    let mut rob = Robot::new(0.0);
+    for (p, i) in rob.percept_map.iter() {
+        let best = match p.current {
+            LocationValue::Can => Action::PickUpCan,
+            _ => Action::MoveEast,
+        };
+        rob.q_matrix[*i][usize::from(best)] = 1.0;
+    }
+
+    let mut near = Environment::new(3, 1, (0, 0));
+    near.set_cell((0, 2), LocationValue::Can);
+    let mut unreachable = Environment::new(3, 1, (0, 0));
+    unreachable.set_cell((1, 2), LocationValue::Can);
+
+    let lengths = rollout_lengths(&mut rob, &mut [near, unreachable], 10);
+    assert_eq!(lengths, vec![3, 10]);
+}
+
+#[test]
+fn test_reward_clip() {
+    let mut env = Environment::new(3, 1, (0, 0));
+    env.set_cell((0, 0), LocationValue::Can);
+    env.reward_clip = Some((-1.0, 1.0));
+
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), 1.0);
+    assert_eq!(env.calculate_reward(&Action::MoveSouth), -1.0);
+    assert_eq!(env.calculate_reward(&Action::MoveNorth), 0.0);
+}
+
+#[test]
+fn test_random_reachable_percept() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let mut seen = HashSet::new();
+    for _ in 0..5000 {
+        let p = Percept::random_reachable(&mut rng);
+        assert!(p.is_reachable());
+        seen.insert(p);
+    }
+    assert!(seen.len() > 500);
+}
+
+#[test]
+fn test_episode_timing() {
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(10),
+        n_episodes: 3,
+        m_steps: 200,
+        ..Default::default()
+    };
+
+    let mut rob = Robot::with_seed(0.1, 5);
+    let records = train(&mut rob, &params).unwrap();
+    assert!(records.iter().all(|r| r.duration_micros > 0));
+
+    let untimed = TrainParams {
+        time_episodes: false,
+        ..params
+    };
+    let records = train(&mut rob, &untimed).unwrap();
+    assert!(records.iter().all(|r| r.duration_micros == 0));
+}
+
+#[test]
+fn test_huber_clipped_update() {
+    let p = Environment::new(3, 0, (1, 1)).create_percept();
+
+    let mut rob = Robot::with_seed(0.0, 2);
+    rob.update_rule = UpdateRule::HuberClipped { delta: 1.0 };
+    let a = rob.select_action(&p);
+    let (i, j) = (rob.percept_map[&p], usize::from(a));
+
+    // A TD-error of -5.0 is clipped to -1.0 before applying eta.
+    rob.reward(-5.0, 0.5, 0.9, &p, false);
+    assert_eq!(rob.q_matrix[i][j], -0.5);
+
+    // Small TD-errors pass through unchanged.
+    rob.q_matrix[i] = vec![0.0; 5];
+    rob.reward(0.4, 0.5, 0.0, &p, false);
+    assert_eq!(rob.q_matrix[i][j], 0.2);
+}
+
+#[test]
+fn test_detect_policy_cycles() {
+    let mut rob = Robot::new(0.0);
+    for row in rob.q_matrix.iter_mut() {
+        row[usize::from(Action::MoveEast)] = 1.0;
+    }
+
+    let mut env = Environment::new(3, 0, (0, 0));
+    env.wrap_y = true;
+    env.set_cell((1, 1), LocationValue::Can);
+
+    let cycles = detect_policy_cycles(&rob, &env);
+    assert_eq!(cycles.len(), 3);
+    for (x, cycle) in cycles.iter().enumerate() {
+        assert_eq!(cycle, &vec![(x, 0), (x, 1), (x, 2)]);
+    }
+
+    // Without wrapping the robot gets stuck against the east wall.
+    env.wrap_y = false;
+    let cycles = detect_policy_cycles(&rob, &env);
+    assert_eq!(cycles, vec![vec![(0, 2)], vec![(1, 2)], vec![(2, 2)]]);
+}
+
+#[test]
+fn test_reward_normalization() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut rob = Robot::new(0.1);
+    rob.normalize_rewards = true;
+
+    let normalized: Vec<f32> = (0..20000)
+        .map(|_| rob.normalize_reward(rng.random_range(-5.0..10.0)))
+        .collect();
+    let mean = normalized.iter().sum::<f32>() / normalized.len() as f32;
+
+    assert!(mean.abs() < 0.05, "mean was {}", mean);
+    assert!((rob.reward_stats.mean - 2.5).abs() < 0.1);
+}
+
+#[test]
+fn test_write_episodes_jsonl() {
+    let records: Vec<EpisodeRecord> = (0..3)
+        .map(|i| EpisodeRecord {
+            episode_id: i,
+            episode_reward: i as f32 * 1.5,
+            crash_count: i,
+            running_average: if i == 0 { f32::NAN } else { 0.75 },
+            duration_micros: 10,
+            ..Default::default()
+        })
+        .collect();
+
+    let path = std::env::temp_dir().join("rl_agent_test_episodes.jsonl");
+    write_episodes_jsonl(&path, &records).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(contents.lines().count(), 3);
+    for (line, record) in contents.lines().zip(&records) {
+        let value: serde_json::Value = serde_json::from_str(line).unwrap();
+        let object = value.as_object().unwrap();
+        for key in [
+            "episode_id",
+            "episode_reward",
+            "running_average",
+            "crash_count",
+            "duration_micros",
+            "hit_reward_cap",
+            "hit_idle_limit",
+            "env_seed",
+            "mean_greedy_q",
+            "exploration_fraction",
+            "q_updates",
+            "reward_totals",
+            "wasted_pickup_dominated",
+        ] {
+            assert!(object.contains_key(key), "missing {}", key);
+        }
+        assert_eq!(object["episode_id"], record.episode_id);
+        assert_eq!(object["episode_reward"], record.episode_reward as f64);
+        assert_eq!(
+            &serde_json::from_str::<EpisodeRecord>(line)
+                .unwrap()
+                .episode_id,
+            &record.episode_id
+        );
+    }
+}
+
+#[test]
+fn test_action_accuracy() {
+    use Action::*;
+    let mut rob = Robot::new(0.0);
+    for (p, i) in rob.percept_map.iter() {
+        let best = if p.current.is_collectible() {
+            PickUpCan
+        } else if p.north.is_collectible() {
+            MoveNorth
+        } else if p.south.is_collectible() {
+            MoveSouth
+        } else if p.east.is_collectible() {
+            MoveEast
+        } else {
+            MoveWest
+        };
+        rob.q_matrix[*i][usize::from(best)] = 1.0;
+    }
+
+    let mut row = Environment::new(4, 3, (0, 0));
+    for y in 1..4 {
+        row.set_cell((0, y), LocationValue::Can);
+    }
+    let mut column = Environment::new(4, 2, (3, 3));
+    column.set_cell((2, 3), LocationValue::Can);
+    column.set_cell((1, 3), LocationValue::Can);
+
+    assert_eq!(oracle_action(&row), Some(MoveEast));
+    assert_eq!(action_accuracy(&rob, &[row, column]), 1.0);
+
+    // A robot that always picks up only agrees on the pickup steps.
+    let mut picker = Robot::new(0.0);
+    for row in picker.q_matrix.iter_mut() {
+        row[usize::from(PickUpCan)] = 1.0;
+    }
+    let mut env = Environment::new(3, 1, (0, 0));
+    env.set_cell((0, 2), LocationValue::Can);
+    assert_eq!(action_accuracy(&picker, &[env]), 1.0 / 3.0);
+}
+
+#[test]
+fn test_symmetries() {
+    let mut env = Environment::new(4, 1, (3, 3));
+    env.set_cell((0, 1), LocationValue::Can);
+
+    let symmetries = env.symmetries();
+    assert_eq!(symmetries.len(), 8);
+    assert_eq!(symmetries[0].grid, env.grid);
+
+    let distinct: HashSet<Vec<Vec<LocationValue>>> =
+        symmetries.iter().map(|e| e.grid.clone()).collect();
+    assert_eq!(distinct.len(), 8);
+
+    // The robot's corner is fixed by neither a mirror nor a rotation, so the
+    // robot should trace out all four corners.
+    for e in &symmetries {
+        assert_eq!(e.count_cans(), 1);
+        let (x, y) = e.robot_coordinates;
+        assert!((x == 0 || x == 3) && (y == 0 || y == 3));
+    }
+}
+
+#[test]
+fn test_exploration_weights() {
+    let p = Environment::new(3, 0, (1, 1)).create_percept();
+    let mut rob = Robot::with_seed(1.0, 9);
+    rob.exploration_weights = vec![1.0, 1.0, 1.0, 1.0, 0.0];
+
+    let mut seen = HashSet::new();
+    for _ in 0..1000 {
+        seen.insert(rob.select_action(&p));
+    }
+
+    assert!(!seen.contains(&Action::PickUpCan));
+    assert_eq!(seen.len(), 4);
+}
+
+#[test]
+fn test_has_plateaued() {
+    let records = |averages: &[f32]| -> Vec<EpisodeRecord> {
+        averages
+            .iter()
+            .enumerate()
+            .map(|(i, average)| EpisodeRecord {
+                episode_id: i,
+                running_average: *average,
+                ..Default::default()
+            })
+            .collect()
+    };
+
+    let mut rising: Vec<f32> = vec![f32::NAN];
+    rising.extend((0..50).map(|i| i as f32 * 0.5));
+    assert!(!has_plateaued(&records(&rising), 20));
+
+    let mut flat = rising.clone();
+    flat.extend((0..50).map(|i| 25.0 + if i % 2 == 0 { 0.1 } else { -0.1 }));
+    assert!(has_plateaued(&records(&flat), 20));
+
+    assert!(!has_plateaued(&records(&flat[..5]), 20));
+}
+
+#[test]
+fn test_revisit_penalty() {
+    use Action::*;
+    let mut env = Environment::new(4, 0, (0, 0));
+    env.reward_config.revisit_penalty = -0.5;
+    env.revisit_window = 2;
+
+    assert_eq!(
+        env.replay(&[MoveNorth, MoveNorth]),
+        vec![(0.0, (1, 0)), (0.0, (2, 0))]
+    );
+    assert_eq!(env.reward_breakdown(&MoveSouth).shaping, -0.5);
+    assert_eq!(env.replay(&[MoveSouth]), vec![(-0.5, (1, 0))]);
+
+    // (0, 0) has dropped out of the two-position window by now.
+    env.replay(&[MoveEast, MoveWest]);
+    assert_eq!(env.calculate_reward(&MoveSouth), 0.0);
+}
+
+#[test]
+fn test_quantize_round_trip() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let mut rob = Robot::new(0.1);
+    for q in rob.q_matrix.iter_mut().flatten() {
+        *q = rng.random_range(-20.0..60.0);
+    }
+
+    let quantized = rob.quantize();
+    let restored = quantized.dequantize();
+
+    let bound = quantized.scale / 2.0 + 1e-4;
+    for (row, restored_row) in rob.q_matrix.iter().zip(&restored) {
+        for (q, r) in row.iter().zip(restored_row) {
+            assert!((q - r).abs() <= bound, "{} vs {}", q, r);
+        }
+    }
+
+    let constant = Robot::new(0.1).quantize().dequantize();
+    assert!(constant.iter().flatten().all(|q| *q == 0.0));
+}
+
+#[test]
+fn test_crash_is_terminal() {
+    let mut env = Environment::new(3, 0, (1, 0));
+    env.crash_is_terminal = true;
+
+    assert!(!step_env(&mut env, &Action::MoveEast).2);
+    assert!(!step_env(&mut env, &Action::PickUpCan).2);
+
+    let (reward_amount, _, done) = step_env(&mut env, &Action::MoveNorth);
+    assert!(!done);
+    assert_eq!(reward_amount, 0.0);
+
+    let (reward_amount, _, done) = step_env(&mut env, &Action::MoveNorth);
+    assert_eq!(reward_amount, -5.0);
+    assert!(done);
+    assert_eq!(env.crash_count, 1);
+}
+
+#[test]
+fn test_state_uncertainty() {
+    let steady = Environment::new(3, 0, (1, 1)).create_percept();
+    let noisy = Environment::new(3, 0, (0, 0)).create_percept();
+
+    let mut rob = Robot::with_seed(0.0, 6);
+    for i in 0..100 {
+        // With eta = 0 the Q-values never move, so the TD-error is just the
+        // reward.
+        rob.select_action(&steady);
+        rob.reward(2.0, 0.0, 0.9, &steady, false);
+        rob.select_action(&noisy);
+        rob.reward(if i % 2 == 0 { 5.0 } else { -5.0 }, 0.0, 0.9, &noisy, false);
+    }
+
+    let uncertainty = rob.state_uncertainty();
+    assert!(uncertainty[steady.index()] < 1e-6);
+    assert!((uncertainty[noisy.index()] - 25.0).abs() < 1e-3);
+}
+
+#[test]
+fn test_greedy_actions() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let mut rob = Robot::new(0.1);
+    for q in rob.q_matrix.iter_mut().flatten() {
+        *q = rng.random_range(-1.0..1.0);
+    }
+
+    let percepts: Vec<Percept> = (0..200)
+        .map(|_| Percept::random_reachable(&mut rng))
+        .collect();
+    let batched = rob.greedy_actions(&percepts);
+
+    for (p, a) in percepts.iter().zip(batched) {
+        assert_eq!(rob.select_greedy_action(p), a);
+    }
+}
+
+#[test]
+fn test_random_grid_solvable() {
+    let start = (2, 2);
+    for _ in 0..20 {
+        let grid = random_grid_solvable(6, 8, 12, start).unwrap();
+        let flat = grid.iter().flatten();
+        assert_eq!(
+            flat.clone().filter(|s| **s == LocationValue::Wall).count(),
+            12
+        );
+        assert_eq!(flat.filter(|s| **s == LocationValue::Can).count(), 8);
+
+        let reachable = reachable_cells(&grid, start);
+        for (x, row) in grid.iter().enumerate() {
+            for (y, space) in row.iter().enumerate() {
+                if *space == LocationValue::Can {
+                    assert!(reachable.contains(&(x, y)));
+                }
+            }
+        }
+    }
+
+    assert_eq!(
+        random_grid_solvable(3, 5, 8, (0, 0)),
+        Err(GridError::TooCrowded { attempts: 1000 })
+    );
+}
+
+#[test]
+fn test_interior_walls_block_moves() {
+    let mut env = Environment::new(3, 0, (1, 1));
+    env.set_cell((2, 1), LocationValue::Wall);
+
+    assert_eq!(env.create_percept().north, LocationValue::Wall);
+    assert_eq!(env.calculate_reward(&Action::MoveNorth), -5.0);
+    env.transition_state(&Action::MoveNorth);
+    assert_eq!(env.robot_coordinates, (1, 1));
+
+    // Interior walls are ordinary crashes even when the edges are pits.
+    env.edge_behavior = EdgeBehavior::Pit { penalty: -50.0 };
+    assert_eq!(env.calculate_reward(&Action::MoveNorth), -5.0);
+    assert!(!env.done);
+}
+
+#[test]
+fn test_pickup_success_prob() {
+    let mut env = Environment::new(3, 1, (1, 1));
+    env.set_cell((1, 1), LocationValue::Can);
+    env.seed(21);
+    env.pickup_success_prob = 0.0;
+
+    for _ in 0..20 {
+        let (reward_amount, p, _) = step_env(&mut env, &Action::PickUpCan);
+        assert_eq!(reward_amount, -1.0);
+        assert_eq!(p.current, LocationValue::Can);
+    }
+
+    env.pickup_success_prob = 0.5;
+    let mut attempts = 0;
+    while env.count_cans() > 0 {
+        let (reward_amount, _, _) = step_env(&mut env, &Action::PickUpCan);
+        assert_eq!(reward_amount == 10.0, env.count_cans() == 0);
+        attempts += 1;
+    }
+    assert!(attempts < 50);
+}
+
+#[test]
+fn test_custom_reward_fn() {
+    struct Constant;
+    impl RewardFn for Constant {
+        fn reward(&self, _env: &Environment, _a: &Action) -> f32 {
+            3.5
+        }
+    }
+
+    let mut env = Environment::new(3, 1, (1, 1));
+    env.set_cell((1, 1), LocationValue::Can);
+    env.reward_fn = Arc::new(Constant);
+
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), 3.5);
+    assert_eq!(env.calculate_reward(&Action::MoveNorth), 3.5);
+    env.reward_clip = Some((0.0, 1.0));
+    assert_eq!(env.calculate_reward(&Action::MoveSouth), 1.0);
+}
+
+#[test]
+fn test_sensor_importance() {
+    let mut rob = Robot::with_seed(0.0, 4);
+    for (p, i) in rob.percept_map.iter() {
+        if p.current == LocationValue::Can {
+            rob.q_matrix[*i][usize::from(Action::PickUpCan)] = 1.0;
+        }
+    }
+
+    let importance = rob.sensor_importance();
+    assert!(importance[0] > 0.1);
+    for value in &importance[1..] {
+        assert!(value.abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_evaluate_heldout() {
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(5),
+        n_episodes: 20,
+        m_steps: 30,
+        grid_seeds: Some(0..10),
+        ..Default::default()
+    };
+    let mut rob = Robot::with_seed(0.1, 8);
+    train(&mut rob, &params).unwrap();
+
+    let rewards = evaluate_heldout(&rob, &params, 10..15);
+    assert_eq!(rewards.len(), 5);
+    assert_eq!(rewards, evaluate_heldout(&rob, &params, 10..15));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "overlap")]
+fn test_evaluate_heldout_overlapping_seeds() {
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(5),
+        m_steps: 10,
+        grid_seeds: Some(0..10),
+        ..Default::default()
+    };
+    evaluate_heldout(&Robot::with_seed(0.1, 8), &params, 9..12);
+}
+
+#[test]
+fn test_percept_features() {
+    use LocationValue::*;
+    let p = Percept {
+        current: Can,
+        north: Wall,
+        south: Empty,
+        east: Gold,
+        west: Empty,
+        ..Default::default()
+    };
+    let features = p.features();
+    assert_eq!(features.iter().filter(|f| **f == 1.0).count(), 5);
+    assert_eq!(features.iter().filter(|f| **f == 0.0).count(), 10);
+    assert_eq!(features[1], 1.0);
+    assert_eq!(features[5], 1.0);
+    assert_eq!(features[6], 1.0);
+    assert_eq!(features[10], 1.0);
+    assert_eq!(features[12], 1.0);
+}
+
+#[test]
+fn test_linear_robot_update() {
+    use LocationValue::*;
+    let p = Percept {
+        current: Can,
+        north: Empty,
+        south: Wall,
+        east: Empty,
+        west: Empty,
+        ..Default::default()
+    };
+    let mut rob = LinearRobot::with_seed(0.0, 12);
+    rob.previous_choice = Some((p.clone(), Action::PickUpCan));
+    rob.reward(10.0, 0.1, 0.9, &p, false);
+
+    let features = p.features();
+    let pickup = usize::from(Action::PickUpCan);
+    for (i, w) in rob.weights[pickup].iter().enumerate() {
+        match features[i] == 1.0 {
+            true => assert!((w - 1.0).abs() < 1e-6),
+            false => assert_eq!(*w, 0.0),
+        }
+    }
+    assert!(rob.weights[0].iter().all(|w| *w == 0.0));
+    assert!(rob.q_values(&p)[pickup] > 0.0);
+    assert_eq!(rob.select_action(&p), Action::PickUpCan);
+}
+
+#[test]
+fn test_greedy_trajectory_frames() {
+    let rob = Robot::with_seed(0.0, 3);
+    let mut env = Environment::new(4, 2, (0, 0));
+    env.set_cell((0, 0), LocationValue::Can);
+    env.set_cell((1, 0), LocationValue::Can);
+
+    let frames = rob.greedy_trajectory_frames(&mut env, 6);
+    assert_eq!(frames.len(), 7);
+    assert!(frames.iter().all(|f| f.contains('R')));
+}
+
+#[test]
+fn test_pickup_rewards_per_location() {
+    let mut env = Environment::new(3, 1, (1, 1));
+    env.reward_config = RewardConfig {
+        can_pickup: 7.0,
+        empty_pickup: -2.0,
+        wall_pickup: -3.0,
+        ..Default::default()
+    };
+
+    env.set_cell((1, 1), LocationValue::Can);
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), 7.0);
+    env.set_cell((1, 1), LocationValue::Empty);
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), -2.0);
+    env.set_cell((1, 1), LocationValue::Wall);
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), -3.0);
+}
+
+#[test]
+fn test_discounted_return() {
+    let gamma: f32 = 0.9;
+    let rewards = [1.0; 10];
+    let closed_form = (1.0 - gamma.powi(10)) / (1.0 - gamma);
+    assert!((discounted_return(&rewards, gamma) - closed_form).abs() < 1e-5);
+    assert_eq!(
+        discounted_return(&[10.0, -1.0, 5.0], 0.5),
+        10.0 - 0.5 + 1.25
+    );
+    assert_eq!(discounted_return(&[], 0.9), 0.0);
+}
+
+#[test]
+fn test_monte_carlo_update() {
+    use LocationValue::*;
+    let at = |current| Percept {
+        current,
+        north: Empty,
+        south: Wall,
+        east: Empty,
+        west: Wall,
+        ..Default::default()
+    };
+    let mut rob = Robot::with_seed(0.0, 6);
+    rob.update_rule = UpdateRule::MonteCarlo;
+
+    // Wasted pickup, move north, pick up the can there, and waste another
+    // pickup on the emptied cell, revisiting the first state-action.
+    let steps = [
+        (at(Empty), Action::PickUpCan, -1.0),
+        (at(Empty), Action::MoveNorth, 0.0),
+        (at(Can), Action::PickUpCan, 10.0),
+        (at(Empty), Action::PickUpCan, -1.0),
+    ];
+    for (p, a, reward_amount) in &steps {
+        rob.previous_choice = Some((p.clone(), a.clone()));
+        rob.reward(*reward_amount, 0.2, 0.5, p, false);
+    }
+    assert!(rob.q_matrix.iter().flatten().all(|q| *q == 0.0));
+
+    rob.end_episode(0.5);
+    let q = |p: Percept, a: Action| rob.q_matrix[p.index()][usize::from(a)];
+    assert_eq!(
+        q(at(Empty), Action::PickUpCan),
+        discounted_return(&[-1.0, 0.0, 10.0, -1.0], 0.5)
+    );
+    assert_eq!(
+        q(at(Empty), Action::MoveNorth),
+        discounted_return(&[0.0, 10.0, -1.0], 0.5)
+    );
+    assert_eq!(
+        q(at(Can), Action::PickUpCan),
+        discounted_return(&[10.0, -1.0], 0.5)
+    );
+    assert_eq!(
+        rob.return_counts[at(Empty).index()][usize::from(Action::PickUpCan)],
+        1
+    );
+
+    // A second episode averages its return with the first.
+    rob.previous_choice = Some((at(Can), Action::PickUpCan));
+    rob.reward(4.0, 0.2, 0.5, &at(Empty), false);
+    rob.end_episode(0.5);
+    let pickup = usize::from(Action::PickUpCan);
+    assert_eq!(rob.q_matrix[at(Can).index()][pickup], (9.5 + 4.0) / 2.0);
+}
+
+#[test]
+fn test_compare_identical_agents() {
+    let grids: Vec<Environment> = (0..4)
+        .map(|seed| Environment::new_seeded(5, 6, seed))
+        .collect();
+    let mut a = Robot::with_seed(0.2, 13);
+    let mut b = Robot::with_seed(0.2, 13);
+
+    let report = compare_agents(&mut a, &mut b, &grids, 25);
+    assert_eq!(report.outcomes.len(), 4);
+    assert_eq!(report.reward_difference, 0.0);
+    assert_eq!(report.win_rate, 0.0);
+    assert_eq!(report.ties, 4);
+
+    let mut linear = LinearRobot::with_seed(0.2, 13);
+    let report = compare_agents(&mut a, &mut linear, &grids, 25);
+    assert_eq!(report.outcomes.len(), 4);
+}
+
+#[test]
+fn test_reward_config_validation() {
+    assert_eq!(RewardConfig::default().validate(true), Ok(vec![]));
+
+    let config = RewardConfig {
+        empty_pickup: 0.0,
+        ..Default::default()
+    };
+    let problem = RewardConfigError::ProfitableWastedPickup { empty_pickup: 0.0 };
+    assert_eq!(config.validate(true), Err(problem));
+    assert_eq!(config.validate(false).unwrap().len(), 1);
+
+    let mut env = Environment::new(3, 0, (1, 1));
+    env.reward_config = config;
+    for _ in 0..3 {
+        step_env(&mut env, &Action::PickUpCan);
+    }
+    step_env(&mut env, &Action::MoveNorth);
+    assert_eq!(env.wasted_pickups, 3);
+    assert!(env.dominated_by_wasted_pickups(4));
+    assert!(!env.dominated_by_wasted_pickups(10));
+}
+
+#[test]
+fn test_diagonal_sensing() {
+    let mut env = Environment::new(3, 1, (0, 0));
+    env.set_cell((1, 1), LocationValue::Can);
+    assert_eq!(env.create_percept().diagonals(), None);
+
+    env.diagonal_sensing = true;
+    let p = env.create_percept();
+    assert_eq!(p.north_east, Some(LocationValue::Can));
+    assert_eq!(p.north_west, Some(LocationValue::Wall));
+    assert_eq!(p.south_east, Some(LocationValue::Wall));
+    assert_eq!(p.south_west, Some(LocationValue::Wall));
+    assert_eq!(p.east, LocationValue::Empty);
+
+    let rob = Robot::new_diagonal(0.1);
+    assert_eq!(rob.q_matrix.len(), 4_usize.pow(9));
+    assert_eq!(rob.percept_map[&p], p.index());
+}
+
+#[test]
+fn test_stationary_distribution() {
+    let mut rob = Robot::with_seed(0.0, 2);
+    for row in rob.q_matrix.iter_mut() {
+        row[usize::from(Action::MoveEast)] = 1.0;
+    }
+
+    let mut env = Environment::new(4, 0, (0, 0));
+    env.wrap_y = true;
+    let distribution = stationary_distribution(&rob, &env, 50);
+    for row in &distribution {
+        for share in row {
+            assert!((share - row[0]).abs() < 1e-6);
+        }
+    }
+    let total: f32 = distribution.iter().flatten().sum();
+    assert!((total - 1.0).abs() < 1e-5);
+
+    // Without wrapping everyone piles up against the east edge.
+    env.wrap_y = false;
+    let distribution = stationary_distribution(&rob, &env, 50);
+    assert!(distribution.iter().all(|row| (row[3] - 0.25).abs() < 1e-6));
+}
+
+#[test]
+fn test_cached_can_count() {
+    let mut env = Environment::new_seeded(5, 8, 17);
+    assert_eq!(env.count_cans(), 8);
+
+    env.set_cell((0, 0), LocationValue::Gold);
+    env.set_cell((4, 4), LocationValue::Can);
+    env.set_cell((4, 4), LocationValue::Can);
+    let mut expected = recount_cans(&env.grid);
+    assert_eq!(env.count_cans(), expected);
+
+    for x in 0..5 {
+        for y in 0..5 {
+            env.robot_coordinates = (x, y);
+            let had_can = env.grid[x][y] == LocationValue::Can;
+            step_env(&mut env, &Action::PickUpCan);
+            expected -= had_can as usize;
+            assert_eq!(env.count_cans(), expected);
+        }
+    }
+    assert_eq!(env.count_cans(), 0);
+
+    env.reset();
+    assert_eq!(env.count_cans(), 8);
+}
+
+#[test]
+fn test_training_artifact_round_trip() {
+    let params = TrainParams {
+        grid_dimension: 4,
+        can_count: CanCount::Fixed(3),
+        n_episodes: 5,
+        m_steps: 10,
+        grid_seeds: Some(0..5),
+        ..Default::default()
+    };
+    let mut rob = Robot::with_seed(0.1, 99);
+    let records = train(&mut rob, &params).unwrap();
+    let artifact = TrainingArtifact {
+        params,
+        seed: 99,
+        records,
+        final_q: rob.q_matrix.clone(),
+    };
+
+    let path = std::env::temp_dir().join("rl_agent_test_artifact.json");
+    artifact.save(&path).unwrap();
+    let loaded = TrainingArtifact::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // The first running average is NaN, so compare through Debug, which
+    // prints every NaN the same way.
+    assert!(loaded.records[0].running_average.is_nan());
+    assert_eq!(format!("{:?}", loaded), format!("{:?}", artifact));
+}
+
+#[test]
+fn test_novelty_bonus() {
+    let mut env = Environment::new(3, 1, (1, 1));
+    env.set_cell((1, 1), LocationValue::Can);
+    env.consume_cans = false;
+    env.reward_config.novelty_bonus = 2.0;
+
+    assert_eq!(step_env(&mut env, &Action::PickUpCan).0, 12.0);
+    assert_eq!(env.count_cans(), 1);
+    assert_eq!(
+        env.reward_breakdown(&Action::PickUpCan),
+        RewardBreakdown {
+            can_pickup: 10.0,
+            ..Default::default()
+        }
+    );
+    assert_eq!(step_env(&mut env, &Action::PickUpCan).0, 10.0);
+}
+
+#[test]
+fn test_reward_cap() {
+    // Every cell holds a can and the robot always picks up, so it earns 10 on
+    // the first step and wastes a pickup on every step after.
+    let always_pick_up = || {
+        let mut rob = Robot::with_seed(0.0, 5);
+        for row in rob.q_matrix.iter_mut() {
+            row[usize::from(Action::PickUpCan)] = 1.0;
+        }
+        rob
+    };
+    let params = TrainParams {
+        grid_dimension: 3,
+        can_count: CanCount::Fixed(9),
+        n_episodes: 1,
+        m_steps: 20,
+        eta: 0.0,
+        reward_cap: Some(15.0),
+        ..Default::default()
+    };
+
+    let records = train(&mut always_pick_up(), &params).unwrap();
+    assert!(!records[0].hit_reward_cap);
+    assert_eq!(records[0].episode_reward, 10.0 - 19.0);
+
+    let capped = TrainParams {
+        reward_cap: Some(5.0),
+        ..params
+    };
+    let records = train(&mut always_pick_up(), &capped).unwrap();
+    assert!(records[0].hit_reward_cap);
+    assert_eq!(records[0].episode_reward, 10.0);
+}
+
+#[test]
+fn test_percept_similarity() {
+    use LocationValue::*;
+    let p = Percept {
+        current: Can,
+        north: Wall,
+        south: Empty,
+        east: Empty,
+        west: Gold,
+        ..Default::default()
+    };
+    let different = Percept {
+        current: Empty,
+        north: Can,
+        south: Wall,
+        east: Gold,
+        west: Can,
+        ..Default::default()
+    };
+    let partial = Percept {
+        current: Can,
+        south: Wall,
+        west: Gold,
+        ..different.clone()
+    };
+
+    assert_eq!(p.similarity(&p), 5);
+    assert_eq!(p.similarity(&different), 0);
+    assert_eq!(p.similarity(&partial), 2);
+    assert_eq!(partial.similarity(&p), 2);
+}
+
+#[test]
+fn test_single_cell_grid() {
+    let mut env = Environment::new_randomized(1, 1);
+    let p = env.create_percept();
+    assert_eq!(p.current, LocationValue::Can);
+    for neighbor in [p.north, p.south, p.east, p.west] {
+        assert_eq!(neighbor, LocationValue::Wall);
+    }
+
+    let crashing: Vec<Action> = all_actions().into_iter().filter(|a| env.crash(a)).collect();
+    assert_eq!(crashing.len(), 4);
+    assert!(!crashing.contains(&Action::PickUpCan));
+
+    for a in &crashing {
+        step_env(&mut env, a);
+        assert_eq!(env.robot_coordinates, (0, 0));
+    }
+    assert_eq!(env.crash_count, 4);
+    assert_eq!(step_env(&mut env, &Action::PickUpCan).0, 10.0);
+    assert!(env.is_cleared());
+    assert_eq!(env.symmetries().len(), 8);
+}
+
+#[test]
+fn test_episodes_to_threshold() {
+    let averages = [f32::NAN, 1.0, 4.0, 7.5, 6.0, 9.0];
+    let records: Vec<EpisodeRecord> = averages
+        .iter()
+        .enumerate()
+        .map(|(i, average)| EpisodeRecord {
+            episode_id: i,
+            running_average: *average,
+            ..Default::default()
+        })
+        .collect();
+
+    assert_eq!(episodes_to_threshold(&records, 5.0), Some(3));
+    assert_eq!(episodes_to_threshold(&records, 9.0), Some(5));
+    assert_eq!(episodes_to_threshold(&records, 0.0), Some(1));
+    assert_eq!(episodes_to_threshold(&records, 10.0), None);
+}
+
+#[test]
+fn test_shared_environment_pickups() {
+    let shared = SharedEnvironment::new(Environment::new_seeded(4, 10, 31));
+    let cells: Vec<(usize, usize)> = (0..4).flat_map(|x| (0..4).map(move |y| (x, y))).collect();
+
+    let handles: Vec<_> = [false, true]
+        .into_iter()
+        .map(|reversed| {
+            let shared = shared.clone();
+            let mut cells = cells.clone();
+            if reversed {
+                cells.reverse();
+            }
+            std::thread::spawn(move || {
+                cells
+                    .into_iter()
+                    .filter(|cell| shared.step_at(*cell, &Action::PickUpCan).0 == 10.0)
+                    .count()
+            })
+        })
+        .collect();
+
+    let collected: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(collected, 10);
+    assert_eq!(shared.with(|env| env.count_cans()), 0);
+}
+
+#[test]
+fn test_max_possible_reward() {
+    let mut env = Environment::new(4, 3, (0, 0));
+    env.set_cell((0, 1), LocationValue::Can);
+    env.set_cell((2, 2), LocationValue::Can);
+    env.set_cell((3, 3), LocationValue::Can);
+    assert_eq!(env.max_possible_reward(), 30.0);
+
+    // One move to the first can, three to the second and two to the third.
+    env.reward_config.move_penalty = -0.5;
+    assert_eq!(env.max_possible_reward(), 30.0 - 3.0);
+    assert_eq!(env.count_cans(), 3);
+}
+
+#[test]
+fn test_state_abstraction() {
+    struct IgnoreCurrent;
+    impl StateAbstraction for IgnoreCurrent {
+        fn abstract_index(&self, p: &Percept) -> usize {
+            Percept {
+                current: LocationValue::Empty,
+                ..p.clone()
+            }
+            .index()
+                / sensed_locations(false).len()
+        }
+
+        fn num_states(&self) -> usize {
+            sensed_locations(false).len().pow(4)
+        }
+    }
+
+    let mut rob = Robot::with_abstraction(0.0, 3, IgnoreCurrent);
+    assert_eq!(rob.q_matrix.len(), 256);
+
+    let on_can = Percept {
+        current: LocationValue::Can,
+        north: LocationValue::Wall,
+        ..Default::default()
+    };
+    let on_empty = Percept {
+        current: LocationValue::Empty,
+        ..on_can.clone()
+    };
+    assert_eq!(rob.percept_map[&on_can], rob.percept_map[&on_empty]);
+
+    rob.q_matrix[rob.percept_map[&on_can]][usize::from(Action::MoveEast)] = 1.0;
+    assert_eq!(
+        rob.greedy_actions(&[on_can, on_empty]),
+        vec![Action::MoveEast, Action::MoveEast]
+    );
+}
+
+#[test]
+fn test_divergence_check() {
+    let mut rob = Robot::with_seed(0.0, 1);
+    assert_eq!(rob.divergence_check(), None);
+
+    rob.q_matrix[40][2] = f32::NEG_INFINITY;
+    assert_eq!(
+        rob.divergence_check(),
+        Some(DivergenceKind::NegativeInfinity)
+    );
+    rob.q_matrix[40][2] = f32::INFINITY;
+    assert_eq!(
+        rob.divergence_check(),
+        Some(DivergenceKind::PositiveInfinity)
+    );
+    rob.q_matrix[3][0] = f32::NAN;
+    assert_eq!(rob.divergence_check(), Some(DivergenceKind::NaN));
+}
+
+#[test]
+fn test_random_grid_spread() {
+    let grid = random_grid_spread(6, 10).unwrap();
+    assert_eq!(recount_cans(&grid), 10);
+    for x in 0..6 {
+        for y in 0..6 {
+            if grid[x][y] != LocationValue::Can {
+                continue;
+            }
+            assert!(x == 5 || grid[x + 1][y] != LocationValue::Can);
+            assert!(y == 5 || grid[x][y + 1] != LocationValue::Can);
+        }
+    }
+
+    assert_eq!(
+        random_grid_spread(3, 6),
+        Err(GridError::TooCrowded {
+            attempts: MAX_GRID_ATTEMPTS
+        })
+    );
+}
+
+#[test]
+fn test_tie_break_prefer_pickup() {
+    let p = Percept {
+        current: LocationValue::Can,
+        ..Default::default()
+    };
+    let mut rob = Robot::with_seed(0.0, 10);
+    rob.tie_break = TieBreak::PreferPickup;
+    let row = rob.percept_map[&p];
+    rob.q_matrix[row] = vec![3.0, 1.0, 3.0, 3.0, 3.0];
+
+    for _ in 0..20 {
+        assert_eq!(rob.max_action_for_percept(&p), (Action::PickUpCan, 3.0));
+    }
+
+    rob.q_matrix[row][usize::from(Action::PickUpCan)] = 2.0;
+    for _ in 0..20 {
+        let (a, q) = rob.max_action_for_percept(&p);
+        assert_ne!(a, Action::PickUpCan);
+        assert_eq!(q, 3.0);
+    }
+}
+
+#[test]
+fn test_transition_model() {
+    let mut env = Environment::new(3, 1, (0, 0));
+    env.set_cell((1, 0), LocationValue::Can);
+    let start = env.create_percept();
+
+    let mut rob = Robot::with_seed(0.0, 2);
+    let (_, next, _) = step_env(&mut env.clone(), &Action::MoveNorth);
+    for _ in 0..5 {
+        let mut episode = env.clone();
+        rob.previous_choice = Some((start.clone(), Action::MoveNorth));
+        let (reward_amount, resulting_percept, _) = step_env(&mut episode, &Action::MoveNorth);
+        rob.reward(reward_amount, 0.2, 0.9, &resulting_percept, false);
+    }
+    assert!(rob.transition_probs(&start, &Action::MoveNorth).is_empty());
+
+    rob.transition_model = Some(TransitionModel::default());
+    for _ in 0..5 {
+        let mut episode = env.clone();
+        rob.previous_choice = Some((start.clone(), Action::MoveNorth));
+        let (reward_amount, resulting_percept, _) = step_env(&mut episode, &Action::MoveNorth);
+        rob.reward(reward_amount, 0.2, 0.9, &resulting_percept, false);
+    }
+    assert_eq!(
+        rob.transition_probs(&start, &Action::MoveNorth),
+        vec![(next, 1.0)]
+    );
+    assert!(rob.transition_probs(&start, &Action::MoveEast).is_empty());
+}
+
+#[test]
+fn test_dyna_q_planning() {
+    // Walk back and forth past a can, picking it up on the way.
+    let actions = [
+        Action::MoveNorth,
+        Action::PickUpCan,
+        Action::MoveEast,
+        Action::MoveWest,
+        Action::MoveSouth,
+        Action::MoveNorth,
+        Action::PickUpCan,
+    ];
+    let changes_per_step = |update_rule: UpdateRule| {
+        let mut rob = Robot::with_seed(0.0, 20);
+        rob.update_rule = update_rule;
+        let mut env = Environment::new(3, 1, (0, 0));
+        env.set_cell((1, 0), LocationValue::Can);
+
+        let mut changes = 0;
+        for a in &actions {
+            rob.previous_choice = Some((env.create_percept(), a.clone()));
+            let (reward_amount, resulting_percept, _) = step_env(&mut env, a);
+            let before = rob.q_matrix.clone();
+            rob.reward(reward_amount, 0.5, 0.9, &resulting_percept, false);
+            changes += before
+                .iter()
+                .flatten()
+                .zip(rob.q_matrix.iter().flatten())
+                .filter(|(old, new)| old != new)
+                .count();
+        }
+        changes as f32 / actions.len() as f32
+    };
+
+    let plain = changes_per_step(UpdateRule::QLearning);
+    let dyna = changes_per_step(UpdateRule::DynaQ { planning_steps: 10 });
+    assert!(dyna > plain, "{} <= {}", dyna, plain);
+}
+
+#[test]
+fn test_reward_per_crash() {
+    let record = |episode_reward, crash_count| EpisodeRecord {
+        episode_reward,
+        crash_count,
+        ..Default::default()
+    };
+
+    assert_eq!(record(120.0, 3).reward_per_crash(), 30.0);
+    assert_eq!(record(-8.0, 0).reward_per_crash(), -8.0);
+    assert_eq!(
+        run_reward_per_crash(&[record(120.0, 3), record(60.0, 2)]),
+        30.0
+    );
+}
+
+#[test]
+fn test_sensor_range() {
+    let mut env = Environment::new(4, 1, (0, 1));
+    env.set_cell((2, 1), LocationValue::Can);
+    assert_eq!(env.create_percept().north, LocationValue::Empty);
+
+    env.sensor_range = 2;
+    let p = env.create_percept();
+    assert_eq!(p.north, LocationValue::Can);
+    assert_eq!(p.east, LocationValue::Empty);
+    assert_eq!(p.south, LocationValue::Wall);
+
+    // With the can gone, the far edge comes into view only at range 4.
+    env.set_cell((2, 1), LocationValue::Empty);
+    env.sensor_range = 3;
+    assert_eq!(env.create_percept().north, LocationValue::Empty);
+    env.sensor_range = 4;
+    assert_eq!(env.create_percept().north, LocationValue::Wall);
+}
+
+#[test]
+fn test_action_gap() {
+    let p = Percept::default();
+    let mut rob = Robot::with_seed(0.0, 1);
+    let row = rob.percept_map[&p];
+
+    rob.q_matrix[row] = vec![1.0, 4.5, -2.0, 3.0, 0.0];
+    assert_eq!(rob.action_gap(&p), 1.5);
+    rob.q_matrix[row] = vec![1.0, 4.5, -2.0, 4.5, 0.0];
+    assert_eq!(rob.action_gap(&p), 0.0);
+}
+
+#[test]
+fn test_weights_csv_non_finite() {
+    let mut rob = Robot::with_seed(0.0, 1);
+    let p = Percept::default();
+    let row = rob.percept_map[&p];
+    rob.q_matrix[row] = vec![f32::INFINITY, f32::NEG_INFINITY, f32::NAN, 1.5, -0.25];
+
+    let path = std::env::temp_dir().join("rl_agent_test_weights.csv");
+    rob.weights_csv(&path).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut lines = contents.lines();
+    assert_eq!(lines.next().unwrap().split(',').count(), 10);
+    let fields: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert_eq!(fields[5..], ["inf", "-inf", "nan", "1.5", "-0.25"]);
+    let parsed: Vec<f32> = fields[5..].iter().map(|f| f.parse().unwrap()).collect();
+    assert_eq!(parsed[0], f32::INFINITY);
+    assert_eq!(parsed[1], f32::NEG_INFINITY);
+    assert!(parsed[2].is_nan());
+
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+}
+
+#[test]
+fn test_warm_start_from_oracle() {
+    use LocationValue::*;
+    let mut rob = Robot::with_seed(0.0, 1);
+    rob.warm_start_from_oracle(2.0);
+
+    let can_north = Percept {
+        north: Can,
+        south: Wall,
+        ..Default::default()
+    };
+    assert_eq!(rob.greedy_actions(&[can_north]), vec![Action::MoveNorth]);
+
+    let on_gold = Percept {
+        current: Gold,
+        east: Can,
+        ..Default::default()
+    };
+    assert_eq!(rob.greedy_actions(&[on_gold]), vec![Action::PickUpCan]);
+
+    let nothing = Percept::default();
+    assert!(
+        rob.q_matrix[rob.percept_map[&nothing]]
+            .iter()
+            .all(|q| *q == 0.0)
+    );
+}
+
+#[test]
+fn test_episode_seeds() {
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(6),
+        n_episodes: 4,
+        m_steps: 10,
+        base_seed: Some(123),
+        ..Default::default()
+    };
+    let records = train(&mut Robot::with_seed(0.1, 1), &params).unwrap();
+    let seeds: Vec<u64> = records.iter().map(|e| e.env_seed.unwrap()).collect();
+    assert_eq!(seeds[2], episode_seed(123, 2));
+
+    let again = train(&mut Robot::with_seed(0.1, 1), &params).unwrap();
+    assert_eq!(
+        again
+            .iter()
+            .map(|e| e.env_seed.unwrap())
+            .collect::<Vec<_>>(),
+        seeds
+    );
+
+    // The grid episode 2 actually trained on, as opposed to one rebuilt from
+    // the same seed.
+    let mut robot = Robot::with_seed(0.1, 1);
+    let mut episodes = EpisodeIter::new(&mut robot, &params);
+    episodes.by_ref().take(2).for_each(drop);
+    let (seed, used) = episodes.episode_start(2);
+    assert_eq!(episodes.next().unwrap().env_seed, seed);
+
+    let rebuilt = Environment::from_episode_seed(records[2].env_seed.unwrap(), 5, 6);
+    assert_eq!(rebuilt.grid, used.grid);
+    assert_eq!(rebuilt.robot_coordinates, used.robot_coordinates);
+    assert_eq!(rebuilt.count_cans(), 6);
+    let independent = Environment::new_seeded(5, 6, episode_seed(123, 2));
+    assert_eq!(independent.grid, used.grid);
+}
+
+#[test]
+fn test_stable_fraction() {
+    let mut env = Environment::new(3, 1, (1, 1));
+    env.set_cell((1, 1), LocationValue::Can);
+    let start = env.create_percept();
+
+    // Record each action once from the start, then make the Q-values exactly
+    // the one-step lookahead values, so the policy can't improve.
+    let mut model = TransitionModel::default();
+    for a in all_actions() {
+        let (reward_amount, next, _) = step_env(&mut env.clone(), &a);
+        model.record(&start, &a, reward_amount, &next);
+    }
+    let mut rob = Robot::with_seed(0.0, 4);
+    let row = rob.percept_map[&start];
+    for a in all_actions() {
+        let mean_reward = model.rewards[&(start.clone(), a.clone())].mean;
+        rob.q_matrix[row][usize::from(a)] = mean_reward;
+    }
+    rob.transition_model = Some(model);
+
+    assert_eq!(rob.stable_fraction(0.0, 10, || env.clone()), 1.0);
+
+    // Preferring a move over the pickup is unstable.
+    rob.q_matrix[row][usize::from(Action::MoveNorth)] = 50.0;
+    assert_eq!(rob.stable_fraction(0.0, 10, || env.clone()), 0.0);
+}
+
+#[test]
+fn test_action_masking() {
+    let p = Percept {
+        north: LocationValue::Wall,
+        ..Default::default()
+    };
+    let mut rob = Robot::with_seed(0.5, 8);
+    rob.action_masking = true;
+    rob.q_matrix[rob.percept_map[&p]][usize::from(Action::MoveNorth)] = 5.0;
+
+    for _ in 0..200 {
+        assert_ne!(rob.select_action(&p), Action::MoveNorth);
+    }
+    let counts = &rob.action_counts[rob.percept_map[&p]];
+    assert!(
+        counts
+            .iter()
+            .enumerate()
+            .all(|(i, n)| (i == 0) == (*n == 0))
+    );
+
+    rob.selection_policy = SelectionPolicy::Ucb1 { c: 1.0 };
+    for _ in 0..50 {
+        assert_ne!(rob.select_action(&p), Action::MoveNorth);
+    }
+}
+
+#[test]
+fn test_expected_clear_time() {
+    let mut rob = Robot::with_seed(0.0, 2);
+    let p = Percept {
+        current: LocationValue::Can,
+        north: LocationValue::Wall,
+        south: LocationValue::Wall,
+        east: LocationValue::Wall,
+        west: LocationValue::Wall,
+        ..Default::default()
+    };
+    let row = rob.percept_map[&p];
+    rob.q_matrix[row][usize::from(Action::PickUpCan)] = 1.0;
+
+    // On a single cell holding the only can, one pickup clears the grid.
+    assert_eq!(expected_clear_time(&rob, 1, 1, 10, 50), 1.0);
+
+    // A robot that never picks up fails every trial and is charged the cap.
+    rob.q_matrix[row][usize::from(Action::PickUpCan)] = -1.0;
+    assert_eq!(expected_clear_time(&rob, 1, 1, 10, 50), 50.0);
+}
+
+#[test]
+fn test_greedy_evaluation_is_reproducible() {
+    // An untrained robot ties everywhere, which used to be broken at random.
+    let mut rob = Robot::with_seed(0.0, 4);
+    let trajectory = |rob: &mut Robot| {
+        let mut env = Environment::new_seeded(6, 8, 21);
+        let mut positions = vec![];
+        for _ in 0..40 {
+            let a = rob.select_greedy_action(&env.create_percept());
+            step_env(&mut env, &a);
+            positions.push(env.robot_coordinates);
+        }
+        positions
+    };
+
+    let first = trajectory(&mut rob);
+    for _ in 0..5 {
+        assert_eq!(trajectory(&mut rob), first);
+    }
+}
+
+#[test]
+fn test_cumulative_regret() {
+    let agent = [2.0, 5.0, 9.0, 10.0, 10.0];
+    let oracle = [10.0, 10.0, 10.0, 10.0, 10.0];
+    let records: Vec<EpisodeRecord> = agent
+        .iter()
+        .enumerate()
+        .map(|(i, reward)| EpisodeRecord {
+            episode_id: i,
+            episode_reward: *reward,
+            running_average: f32::NAN,
+            ..Default::default()
+        })
+        .collect();
+
+    let regret = cumulative_regret(&records, &oracle);
+    assert_eq!(regret, vec![8.0, 13.0, 14.0, 14.0, 14.0]);
+    assert!(regret.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[test]
+fn test_with_wall_border() {
+    let inner = Environment::new_seeded(4, 5, 17);
+    let start = inner.robot_coordinates;
+    let mut env = inner.clone().with_wall_border();
+
+    assert_eq!(env.grid_dimension, 6);
+    assert_eq!(env.count_cans(), 5);
+    assert_eq!(env.robot_coordinates, (start.0 + 1, start.1 + 1));
+    for x in 0..6 {
+        for y in 0..6 {
+            let on_border = x == 0 || y == 0 || x == 5 || y == 5;
+            match on_border {
+                true => assert_eq!(env.grid[x][y], LocationValue::Wall),
+                false => assert_eq!(env.grid[x][y], inner.grid[x - 1][y - 1]),
+            }
+        }
+    }
+
+    // Walk into the southern border and make sure it holds.
+    for _ in 0..6 {
+        env.calculate_reward(&Action::MoveSouth);
+        env.transition_state(&Action::MoveSouth);
+    }
+    assert_eq!(env.robot_coordinates.0, 1);
+    assert!(env.crash_count > 0);
+    assert_eq!(env.create_percept().south, LocationValue::Wall);
+
+    // Resetting draws a new inner grid but keeps the border.
+    let mut env = env.with_wall_border();
+    for _ in 0..10 {
+        env.reset();
+        assert_eq!(env.grid_dimension, 8);
+        assert_eq!(env.count_cans(), 5);
+        let (x, y) = env.robot_coordinates;
+        assert!((2..6).contains(&x) && (2..6).contains(&y));
+        for x in 0..8 {
+            for y in 0..8 {
+                if x < 2 || y < 2 || x > 5 || y > 5 {
+                    assert_eq!(env.grid[x][y], LocationValue::Wall);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_policy_dot() {
+    let mut env = Environment::new_seeded(3, 2, 5);
+    env.set_cell((1, 1), LocationValue::Wall);
+    let mut rob = Robot::with_seed(0.0, 5);
+    for row in rob.q_matrix.iter_mut() {
+        row[usize::from(Action::MoveEast)] = 1.0;
+    }
+
+    let dot = rob.policy_dot(&env);
+    assert!(dot.starts_with("digraph policy {\n"));
+    assert!(dot.ends_with("}\n"));
+
+    let nodes = dot.lines().filter(|l| l.contains("[label=\"(")).count();
+    let edges: Vec<&str> = dot.lines().filter(|l| l.contains(" -> ")).collect();
+    assert_eq!(nodes, 9);
+    assert_eq!(edges.len(), 8);
+    for edge in &edges {
+        assert!(edge.trim_start().starts_with('c') && edge.ends_with("[label=\"E\"];"));
+    }
+    assert!(dot.contains("    c0_0 -> c0_1 [label=\"E\"];"));
+    // Blocked by the wall in the middle, and by the east edge.
+    assert!(dot.contains("    c1_0 -> c1_0 [label=\"E\"];"));
+    assert!(dot.contains("    c2_2 -> c2_2 [label=\"E\"];"));
+}
+
+#[test]
+fn test_collect_trajectories() {
+    let grids: Vec<Environment> = (0..3).map(|i| Environment::new_seeded(5, 8, i)).collect();
+    let mut rob = Robot::with_seed(0.3, 9);
+    let trajectories = collect_trajectories(&mut rob, &grids, 30);
+    assert_eq!(trajectories.len(), 3);
+
+    for (grid, trajectory) in grids.iter().zip(&trajectories) {
+        assert_eq!(trajectory.steps.len(), 30);
+
+        // Replaying the recorded actions reproduces the rewards and percepts.
+        let mut env = grid.clone();
+        let mut total = 0.0;
+        for (percept, action, reward, next) in &trajectory.steps {
+            assert_eq!(*percept, env.create_percept());
+            let (replayed, next_percept, _) = step_env(&mut env, action);
+            assert_eq!(replayed, *reward);
+            assert_eq!(next_percept, *next);
+            total += replayed;
+        }
+        assert_eq!(trajectory.total_reward(), total);
+    }
+
+    // Episodes that end early are cut short.
+    let mut pit = Environment::new_seeded(3, 0, 4);
+    pit.edge_behavior = EdgeBehavior::Pit { penalty: -10.0 };
+    let mut rob = Robot::with_seed(0.0, 9);
+    for row in rob.q_matrix.iter_mut() {
+        row[usize::from(Action::MoveNorth)] = 1.0;
+    }
+    let trajectory = &collect_trajectories(&mut rob, &[pit.clone()], 30)[0];
+    assert_eq!(trajectory.steps.len(), 3 - pit.robot_coordinates.0);
+}
+
+#[test]
+fn test_mean_greedy_q() {
+    // With eta at zero nothing is learned, and distinct values in every row
+    // make the greedy action unique.
+    let mut rob = Robot::with_seed(0.0, 3);
+    for (i, row) in rob.q_matrix.iter_mut().enumerate() {
+        for (j, q) in row.iter_mut().enumerate() {
+            *q = ((i * 7 + j * 3) % 11) as f32;
+        }
+    }
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(6),
+        n_episodes: 1,
+        m_steps: 6,
+        eta: 0.0,
+        base_seed: Some(40),
+        ..Default::default()
+    };
+    let records = train(&mut rob, &params).unwrap();
+
+    let mut env = Environment::from_episode_seed(episode_seed(40, 0), 5, 6);
+    let mut greedy = vec![];
+    for _ in 0..6 {
+        let p = env.create_percept();
+        let row = &rob.q_matrix[rob.percept_map[&p]];
+        let best = argmax_first(row);
+        greedy.push(row[best]);
+        if step_env(&mut env, &Action::from(best)).2 {
+            break;
+        }
+    }
+    let expected = greedy.iter().sum::<f32>() / greedy.len() as f32;
+    assert_eq!(records[0].mean_greedy_q, expected);
+}
+
+#[test]
+fn test_verify_movement_symmetry() {
+    let env = Environment::new_randomized_with_start(6, 10, StartPosition::Fixed(2, 3));
+    assert_eq!(env.verify_movement_symmetry(), Ok(()));
+
+    let mut wrapped = env.clone();
+    wrapped.wrap_x = true;
+    wrapped.wrap_y = true;
+    assert_eq!(wrapped.verify_movement_symmetry(), Ok(()));
+}
+
+#[test]
+fn test_can_count_range() {
+    let mut env = Environment::new_randomized(8, CanCount::Uniform { min: 10, max: 20 });
+    env.seed(6);
+
+    let mut counts = vec![];
+    for _ in 0..500 {
+        env.reset();
+        assert_eq!(env.count_cans(), env.initial_number_of_cans);
+        counts.push(env.count_cans());
+    }
+    assert!(counts.iter().all(|n| (10..=20).contains(n)));
+    let mean = counts.iter().sum::<usize>() as f32 / counts.len() as f32;
+    assert!((mean - 15.0).abs() < 0.5, "mean can count {}", mean);
+
+    let mut fixed = Environment::new_randomized(8, 12);
+    fixed.reset();
+    assert_eq!(fixed.count_cans(), 12);
+}
+
+#[test]
+fn test_actions_used() {
+    let mut rob = Robot::with_seed(0.1, 1);
+    for row in rob.q_matrix.iter_mut() {
+        row[usize::from(Action::MoveEast)] = 1.0;
+    }
+    assert_eq!(rob.actions_used(), HashSet::from([Action::MoveEast]));
+
+    let p = Percept {
+        current: LocationValue::Can,
+        ..Default::default()
+    };
+    rob.q_matrix[rob.percept_map[&p]][usize::from(Action::PickUpCan)] = 2.0;
+    assert_eq!(
+        rob.actions_used(),
+        HashSet::from([Action::MoveEast, Action::PickUpCan])
+    );
+}
+
+#[test]
+fn test_visit_count_eta() {
+    let p = Percept {
+        current: LocationValue::Can,
+        ..Default::default()
+    };
+    let mut rob = Robot::with_seed(0.0, 2);
+    rob.eta_schedule = EtaSchedule::VisitCount;
+    rob.exploration_weights = vec![0.0, 0.0, 0.0, 0.0, 1.0];
+    assert_eq!(rob.effective_eta(&p, &Action::PickUpCan, 0.2), 1.0);
+
+    // The first update uses 1/2 rather than the eta passed in.
+    assert_eq!(rob.select_action(&p), Action::PickUpCan);
+    rob.reward(1.0, 0.2, 0.0, &p, false);
+    assert_eq!(rob.q_matrix[rob.percept_map[&p]][4], 0.5);
+
+    // The effective eta halves each time the visit count plus one doubles.
+    for (selections, expected) in [(3, 0.25), (7, 0.125), (15, 0.0625)] {
+        while rob.action_counts[rob.percept_map[&p]][4] < selections {
+            rob.select_action(&p);
+            rob.reward(1.0, 0.2, 0.0, &p, false);
+        }
+        assert_eq!(rob.effective_eta(&p, &Action::PickUpCan, 0.2), expected);
+    }
+
+    // Other actions in the same percept keep their own counts.
+    assert_eq!(rob.effective_eta(&p, &Action::MoveNorth, 0.2), 1.0);
+}
+
+#[test]
+fn test_sweep() {
+    let base_params = TrainParams {
+        grid_dimension: 4,
+        can_count: CanCount::Fixed(4),
+        n_episodes: 6,
+        m_steps: 20,
+        time_episodes: false,
+        base_seed: Some(3),
+        ..Default::default()
+    };
+    let grid = [(0.1, 0.9), (0.2, 0.9), (0.5, 0.5)];
+    let results = sweep(&grid, &base_params, 2).unwrap();
+
+    assert_eq!(results.len(), grid.len());
+    for ((eta, gamma), (r_eta, r_gamma, mean)) in grid.iter().zip(&results) {
+        assert_eq!((eta, gamma), (r_eta, r_gamma));
+        assert!(mean.is_finite());
+    }
+
+    // The sweep is reproducible, since every run is seeded.
+    assert_eq!(sweep(&grid, &base_params, 2).unwrap(), results);
+}
+
+#[test]
+fn test_on_can_bonus() {
+    let mut env = Environment::new(3, 0, (1, 1));
+    env.reward_config.move_penalty = -0.5;
+    env.reward_config.on_can_bonus = 0.25;
+    env.set_cell((1, 1), LocationValue::Can);
+
+    assert_eq!(env.calculate_reward(&Action::MoveEast), -0.25);
+    assert_eq!(env.calculate_reward(&Action::PickUpCan), 10.0);
+
+    // Off the can, moving earns just the move penalty.
+    env.transition_state(&Action::MoveEast);
+    assert_eq!(env.calculate_reward(&Action::MoveWest), -0.5);
+}
+
+#[test]
+fn test_bellman_residual() {
+    let mut rob = Robot::with_seed(0.0, 1);
+    assert_eq!(rob.bellman_residual(0.9), (0.0, 0.0));
+
+    // Picking up forever on the same percept for 1 each step is worth
+    // 1 / (1 - 0.9) = 10, and moving south onto the can from beside it is
+    // worth 0.9 * 10.
+    let on_can = Percept {
+        current: LocationValue::Can,
+        ..Default::default()
+    };
+    let beside = Percept {
+        south: LocationValue::Can,
+        ..Default::default()
+    };
+    let mut model = TransitionModel::default();
+    for _ in 0..5 {
+        model.record(&on_can, &Action::PickUpCan, 1.0, &on_can);
+        model.record(&beside, &Action::MoveSouth, 0.0, &on_can);
+    }
+    rob.transition_model = Some(model);
+    rob.q_matrix[rob.percept_map[&on_can]][usize::from(Action::PickUpCan)] = 10.0;
+    rob.q_matrix[rob.percept_map[&beside]][usize::from(Action::MoveSouth)] = 9.0;
+
+    let (max, mean) = rob.bellman_residual(0.9);
+    assert!(max < 1e-5 && mean < 1e-5);
+
+    rob.q_matrix[rob.percept_map[&beside]][usize::from(Action::MoveSouth)] = 8.0;
+    let (max, mean) = rob.bellman_residual(0.9);
+    assert!((max - 1.0).abs() < 1e-5);
+    assert!((mean - 0.5).abs() < 1e-5);
+}
+
+#[test]
+fn test_rng_snapshot() {
+    let mut rob = Robot::with_seed(0.5, 77);
+    let percepts: Vec<Percept> = {
+        let mut rng = StdRng::seed_from_u64(1);
+        (0..50)
+            .map(|_| Percept::random_reachable(&mut rng))
+            .collect()
+    };
+    for p in &percepts[..10] {
+        rob.select_action(p);
+    }
+
+    let snapshot = rob.rng_snapshot();
+    let first: Vec<Action> = percepts.iter().map(|p| rob.select_action(p)).collect();
+    rob.restore_rng(snapshot);
+    let replayed: Vec<Action> = percepts.iter().map(|p| rob.select_action(p)).collect();
+    assert_eq!(first, replayed);
+}
+
+#[cfg(feature = "color")]
+#[test]
+fn test_render_colored() {
+    let mut env = Environment::new(3, 0, (1, 1));
+    env.set_cell((0, 0), LocationValue::Can);
+    env.set_cell((2, 2), LocationValue::Wall);
+    let colored = env.render_colored();
+
+    assert!(colored.contains("\x1b[34mR\x1b[0m"));
+    assert!(colored.contains("\x1b[32mC\x1b[0m"));
+    assert!(colored.contains("\x1b[90m#\x1b[0m"));
+
+    // Stripping the escape codes gives back the plain rendering.
+    let plain = colored
+        .replace("\x1b[0m", "")
+        .replace("\x1b[34m", "")
+        .replace("\x1b[32m", "")
+        .replace("\x1b[90m", "");
+    assert_eq!(plain, env.render());
+}
+
+#[test]
+fn test_exploration_fraction() {
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(5),
+        n_episodes: 3,
+        m_steps: 40,
+        eta: 0.0,
+        time_episodes: false,
+        base_seed: Some(8),
+        ..Default::default()
+    };
+
+    let mut rob = Robot::with_seed(1.0, 8);
+    let records = train(&mut rob, &params).unwrap();
+    assert!(records.iter().all(|e| e.exploration_fraction == 1.0));
+    assert_eq!(rob.forced_random_choices, 0);
+
+    // An untrained greedy robot picks at random only because every row is
+    // tied, which isn't counted as exploring.
+    let mut rob = Robot::with_seed(0.0, 8);
+    let records = train(&mut rob, &params).unwrap();
+    assert!(records.iter().all(|e| e.exploration_fraction == 0.0));
+    assert_eq!(rob.exploratory_choices, 0);
+    assert_eq!(rob.forced_random_choices, 120);
+}
+
+#[test]
+fn test_policy_fingerprint() {
+    let mut rng = StdRng::seed_from_u64(30);
+    let mut rob = Robot::with_seed(0.1, 30);
+    for q in rob.q_matrix.iter_mut().flatten() {
+        *q = rng.random_range(-1.0..1.0);
+    }
+    let fingerprint = rob.policy_fingerprint();
+    assert_eq!(rob.policy_fingerprint(), fingerprint);
+
+    // Scaling every Q-value keeps the policy, and so the fingerprint.
+    let mut scaled = Robot::with_seed(0.1, 31);
+    for (row, original) in scaled.q_matrix.iter_mut().zip(&rob.q_matrix) {
+        *row = original.iter().map(|q| q * 2.0).collect();
+    }
+    assert_eq!(scaled.policy_fingerprint(), fingerprint);
+
+    let p = Percept::random_reachable(&mut rng);
+    let row = rob.percept_map[&p];
+    let worst = (0..5)
+        .min_by(|a, b| rob.q_matrix[row][*a].total_cmp(&rob.q_matrix[row][*b]))
+        .unwrap();
+    rob.q_matrix[row][worst] = 5.0;
+    assert_ne!(rob.policy_fingerprint(), fingerprint);
+
+    // Pinned, so a change of hash algorithm doesn't go unnoticed.
+    assert_eq!(
+        Robot::with_seed(0.1, 32).policy_fingerprint(),
+        5182817677565579521
+    );
+}
+
+#[test]
+fn test_energy_and_recharge() {
+    let mut env = Environment::new(5, 0, (0, 0));
+    env.set_energy_budget(3);
+    assert_eq!(env.energy_budget(), Some(3));
+    env.set_cell((0, 2), LocationValue::Recharge);
+
+    assert!(step_env(&mut env, &Action::MoveEast).0 <= 0.0);
+    assert_eq!(env.energy, 2);
+    // Pickups don't use energy.
+    step_env(&mut env, &Action::PickUpCan);
+    assert_eq!(env.energy, 2);
+
+    step_env(&mut env, &Action::MoveEast);
+    assert_eq!(env.robot_coordinates, (0, 2));
+    assert_eq!(env.energy, 3);
+    // Recharge cells look empty unless the robot can sense them, in which
+    // case it needs the larger table.
+    assert_eq!(env.create_percept().current, LocationValue::Empty);
+    env.recharge_sensing = true;
+    let p = env.create_percept();
+    assert_eq!(p.current, LocationValue::Recharge);
+    let abstraction = FullPercept {
+        recharge_sensing: true,
+        ..Default::default()
+    };
+    let rob = Robot::with_abstraction(0.0, 1, abstraction);
+    assert_eq!(rob.q_matrix.len(), 5_usize.pow(5));
+    assert_eq!(rob.percept_map[&p], abstraction.abstract_index(&p));
+
+    // Crashing still costs energy, even on a recharge cell, and running out
+    // ends the episode.
+    step_env(&mut env, &Action::MoveSouth);
+    step_env(&mut env, &Action::MoveNorth);
+    assert_eq!(env.energy, 1);
+    assert!(!env.done);
+    let (_, _, done) = step_env(&mut env, &Action::MoveNorth);
+    assert_eq!(env.energy, 0);
+    assert!(done);
+
+    env.reset();
+    assert_eq!(env.energy, 3);
+}
+
+#[test]
+fn test_robustness() {
+    let mut rng = StdRng::seed_from_u64(14);
+    let mut rob = Robot::with_seed(0.1, 14);
+    for q in rob.q_matrix.iter_mut().flatten() {
+        *q = rng.random_range(-1.0..1.0);
+    }
+
+    assert_eq!(rob.robustness(0.0, 3), 1.0);
+    assert_eq!(rob.robustness(0.1, 3), rob.robustness(0.1, 3));
+
+    let slight = rob.robustness(0.01, 3);
+    let heavy = rob.robustness(10.0, 3);
+    assert!(slight > 0.9);
+    assert!(heavy < slight && heavy < 0.5);
+}
+
+#[test]
+fn test_max_idle_steps() {
+    // On an empty grid, bouncing between the two eastmost columns never earns
+    // or loses anything.
+    let mut rob = Robot::with_seed(0.0, 6);
+    let rows: Vec<(Percept, usize)> = rob
+        .percept_map
+        .iter()
+        .map(|(p, i)| (p.clone(), *i))
+        .collect();
+    for (p, i) in rows {
+        rob.q_matrix[i][usize::from(Action::MoveEast)] = 1.0;
+        if p.east == LocationValue::Wall {
+            rob.q_matrix[i][usize::from(Action::MoveWest)] = 2.0;
+        }
+    }
+    let params = TrainParams {
+        grid_dimension: 4,
+        can_count: CanCount::Fixed(0),
+        n_episodes: 3,
+        m_steps: 50,
+        eta: 0.0,
+        time_episodes: false,
+        base_seed: Some(2),
+        max_idle_steps: Some(7),
+        ..Default::default()
+    };
+
+    let records = train(&mut rob, &params).unwrap();
+    assert!(
+        records
+            .iter()
+            .all(|e| e.hit_idle_limit && e.episode_reward == 0.0)
+    );
+    assert_eq!(rob.visit_counts.iter().sum::<u32>(), 3 * 7);
+
+    let records = train(
+        &mut rob,
+        &TrainParams {
+            max_idle_steps: None,
+            ..params
+        },
+    )
+    .unwrap();
+    assert!(records.iter().all(|e| !e.hit_idle_limit));
+    assert_eq!(rob.visit_counts.iter().sum::<u32>(), 3 * 7 + 3 * 50);
+}
+
+#[test]
+fn test_q_updates() {
+    // Masked moves on an empty grid, never picking up, earn nothing, and an
+    // all-zero Q-matrix has nothing to bootstrap from.
+    let mut rob = Robot::with_seed(0.0, 11);
+    rob.action_masking = true;
+    rob.exploration_weights = vec![1.0, 1.0, 1.0, 1.0, 0.0];
+    let params = TrainParams {
+        grid_dimension: 4,
+        can_count: CanCount::Fixed(0),
+        n_episodes: 2,
+        m_steps: 30,
+        time_episodes: false,
+        base_seed: Some(5),
+        ..Default::default()
+    };
+    let records = train(&mut rob, &params).unwrap();
+    assert!(
+        records
+            .iter()
+            .all(|e| e.episode_reward == 0.0 && e.q_updates == 0)
+    );
+
+    // Letting it pick up on a grid with cans changes things.
+    rob.exploration_weights = vec![1.0; 5];
+    let records = train(
+        &mut rob,
+        &TrainParams {
+            can_count: CanCount::Fixed(6),
+            ..params
+        },
+    )
+    .unwrap();
+    assert!(records[0].q_updates > 0);
+}
+
+#[test]
+fn test_deterministic_environment() {
+    let cans = [(0, 1), (2, 2), (3, 0)];
+    let env = Environment::deterministic(4, (1, 1), &cans);
+
+    assert_eq!(env.count_cans(), 3);
+    assert_eq!(env.initial_number_of_cans, 3);
+    assert_eq!(env.robot_coordinates, (1, 1));
+    for x in 0..4 {
+        for y in 0..4 {
+            let expected = match cans.contains(&(x, y)) {
+                true => LocationValue::Can,
+                false => LocationValue::Empty,
+            };
+            assert_eq!(env.grid[x][y], expected);
+        }
+    }
+    assert_eq!(env.create_percept().south, LocationValue::Can);
+}
+
+#[test]
+#[should_panic(expected = "outside a grid")]
+fn test_deterministic_environment_off_grid() {
+    Environment::deterministic(4, (1, 1), &[(0, 4)]);
+}
+
+#[test]
+fn test_episode_iter() {
+    let params = TrainParams {
+        grid_dimension: 5,
+        can_count: CanCount::Fixed(5),
+        n_episodes: 1000,
+        m_steps: 20,
+        time_episodes: false,
+        base_seed: Some(12),
+        ..Default::default()
+    };
+
+    let mut rob = Robot::with_seed(0.2, 12);
+    let records: Vec<EpisodeRecord> = EpisodeIter::new(&mut rob, &params).take(10).collect();
+    assert_eq!(records.len(), 10);
+    assert_eq!(records[9].episode_id, 9);
+    assert_eq!(rob.visit_counts.iter().sum::<u32>(), 10 * 20);
+
+    // The stream matches what train produces.
+    let mut streamed_rob = Robot::with_seed(0.2, 12);
+    let streamed: Vec<EpisodeRecord> = EpisodeIter::new(&mut streamed_rob, &params)
+        .take(120)
+        .collect();
+    let mut trained_rob = Robot::with_seed(0.2, 12);
+    let trained = train(
+        &mut trained_rob,
+        &TrainParams {
+            n_episodes: 120,
+            ..params
+        },
+    )
+    .unwrap();
+    assert_eq!(format!("{:?}", streamed), format!("{:?}", trained));
+}
+
+#[test]
+fn test_episode_reward_totals() {
+    let params = TrainParams {
+        grid_dimension: 6,
+        can_count: CanCount::Fixed(12),
+        n_episodes: 20,
+        m_steps: 60,
+        time_episodes: false,
+        base_seed: Some(19),
+        ..Default::default()
+    };
+    let mut rob = Robot::with_seed(0.3, 19);
+    let records = train(&mut rob, &params).unwrap();
+
+    for e in &records {
+        assert!((e.reward_totals.total() - e.episode_reward).abs() < 1e-3);
+    }
+    let pickups: f32 = records.iter().map(|e| e.reward_totals.can_pickup).sum();
+    let crashes: f32 = records.iter().map(|e| e.reward_totals.crash).sum();
+    assert!(pickups > 0.0 && crashes < 0.0);
+}
+
+#[test]
+fn test_min_epsilon() {
+    let params = TrainParams {
+        grid_dimension: 3,
+        can_count: CanCount::Fixed(2),
+        n_episodes: 400,
+        m_steps: 2,
+        epsilon_decay: 0.5,
+        min_epsilon: 0.05,
+        time_episodes: false,
+        base_seed: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(params.epsilon_at(0.8, 0), 0.8);
+    assert_eq!(params.epsilon_at(0.8, 50), 0.4);
+    assert_eq!(params.epsilon_at(0.8, 150), 0.1);
+    assert_eq!(params.epsilon_at(0.8, 200), 0.05);
+    assert_eq!(params.epsilon_at(0.8, 10_000), 0.05);
+    assert_eq!(params.epsilon_at(0.01, 10_000), 0.01);
+
+    let mut rob = Robot::with_seed(0.8, 1);
+    train(&mut rob, &params).unwrap();
+    assert_eq!(rob.epsilon, 0.05);
+    assert_eq!(rob.epsilon, params.epsilon_at(0.8, params.n_episodes));
+}
+
+#[test]
+fn test_percept_from_csv_fields() {
+    let mut rng = StdRng::seed_from_u64(202);
+    for _ in 0..50 {
+        let p = Percept::random_reachable(&mut rng);
+        let letters: Vec<String> = [p.current, p.north, p.south, p.east, p.west]
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        let fields: Vec<&str> = letters.iter().map(|l| l.as_str()).collect();
+        assert_eq!(Percept::from_csv_fields(&fields), Ok(p));
+    }
+
+    assert_eq!("G".parse(), Ok(LocationValue::Gold));
+    assert_eq!(
+        "x".parse::<LocationValue>(),
+        Err(ParseError::UnknownLocation("x".to_string()))
+    );
+    assert_eq!(
+        Percept::from_csv_fields(&["E", "C", "W"]),
+        Err(ParseError::FieldCount { found: 3 })
+    );
+}
+
+#[test]
+fn test_corridor_percepts_are_reachable() {
+    let mut env = Environment::new(3, 0, (1, 1));
+    env.set_cell((0, 1), LocationValue::Wall);
+    env.set_cell((2, 1), LocationValue::Wall);
+    let p = env.create_percept();
+    assert_eq!(
+        (p.north, p.south),
+        (LocationValue::Wall, LocationValue::Wall)
+    );
+    assert!(!p.is_reachable());
+    assert!(!env.is_open_grid());
+
+    let mut rob = Robot::with_seed(0.0, 3);
+    rob.open_grid = env.is_open_grid();
+    let row = rob.percept_map[&p];
+    rob.q_matrix[row][usize::from(Action::MoveEast)] = 7.0;
+    rob.zero_unreachable();
+    assert_eq!(rob.q_matrix[row][usize::from(Action::MoveEast)], 7.0);
+    assert!(rob.actions_used().contains(&Action::MoveEast));
+
+    let path = std::env::temp_dir().join("rl_agent_test_corridor_weights.csv");
+    rob.weights_csv(&path).unwrap();
+    let csv = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(csv.lines().any(|line| line.starts_with("E,W,W,E,E,")));
+
+    // Standing on a wall is still impossible.
+    let on_wall = Percept {
+        current: LocationValue::Wall,
+        ..Default::default()
+    };
+    rob.q_matrix[rob.percept_map[&on_wall]][0] = 1.0;
+    rob.zero_unreachable();
+    assert_eq!(rob.q_matrix[rob.percept_map[&on_wall]][0], 0.0);
+
+    assert!(Environment::new(3, 0, (1, 1)).is_open_grid());
+}
+
+#[test]
+fn test_oracle_stops_on_endless_cans() {
+    let mut rob = Robot::with_seed(0.0, 5);
+    let row = rob.percept_map[&Percept {
+        current: LocationValue::Can,
+        ..Default::default()
+    }];
+    rob.q_matrix[row][usize::from(Action::PickUpCan)] = 1.0;
+
+    let mut kept = Environment::deterministic(3, (1, 1), &[(1, 1)]);
+    kept.consume_cans = false;
+    let mut failing = Environment::deterministic(3, (1, 1), &[(1, 1)]);
+    failing.pickup_success_prob = 0.0;
+
+    for env in [kept, failing] {
+        assert_eq!(action_accuracy(&rob, std::slice::from_ref(&env)), 1.0);
+        assert!(env.max_possible_reward().is_finite());
+    }
+}
+
+#[test]
+fn test_action_accuracy_without_collectibles() {
+    let rob = Robot::with_seed(0.0, 6);
+    assert_eq!(
+        action_accuracy(&rob, &[Environment::new(3, 0, (1, 1))]),
+        0.0
+    );
+    assert_eq!(action_accuracy(&rob, &[]), 0.0);
+}
+
+#[test]
+fn test_train_can_count() {
+    let params = TrainParams {
+        grid_dimension: 4,
+        can_count: CanCount::Uniform { min: 2, max: 5 },
+        n_episodes: 20,
+        m_steps: 10,
+        base_seed: Some(8),
+        ..Default::default()
+    };
+
+    for params in [
+        params.clone(),
+        TrainParams {
+            base_seed: None,
+            ..params
+        },
+    ] {
+        let mut robot = Robot::with_seed(0.1, 2);
+        let episodes = EpisodeIter::new(&mut robot, &params);
+        let counts: HashSet<usize> = (0..params.n_episodes)
+            .map(|i| episodes.episode_start(i).1.count_cans())
+            .collect();
+        assert!(counts.iter().all(|n| (2..=5).contains(n)));
+        assert!(counts.len() > 1);
+
+        let records = train(&mut Robot::with_seed(0.1, 2), &params).unwrap();
+        assert_eq!(records.len(), params.n_episodes);
+    }
+
+    // A seed still pins down the whole grid, can count included.
+    let a = Environment::from_episode_seed(episode_seed(8, 3), 4, params.can_count);
+    let b = Environment::from_episode_seed(episode_seed(8, 3), 4, params.can_count);
+    assert_eq!(a.grid, b.grid);
+}
+
+#[test]
+fn test_collect_trajectories_counts_selections() {
+    let mut rob = Robot::with_seed(0.0, 4);
+    let q_before = rob.q_matrix.clone();
+    let trajectories = collect_trajectories(&mut rob, &[Environment::new(3, 0, (1, 1))], 5);
+    assert_eq!(rob.q_matrix, q_before);
+    assert_eq!(
+        rob.visit_counts.iter().sum::<u32>() as usize,
+        trajectories[0].steps.len()
+    );
+}
+
+#[test]
+fn test_planning_uses_visit_count_eta() {
+    let mut rob = Robot::with_seed(0.0, 1);
+    rob.update_rule = UpdateRule::DynaQ { planning_steps: 0 };
+    rob.eta_schedule = EtaSchedule::VisitCount;
+    let env = Environment::new(3, 0, (1, 1));
+    let p = env.create_percept();
+
+    let a = rob.select_action(&p);
+    rob.reward(10.0, 0.9, 0.0, &p, false);
+    let row = rob.percept_map[&p];
+    assert_eq!(rob.q_matrix[row][usize::from(a.clone())], 5.0);
+
+    // One visit so far, so planning steps halfway too rather than by 0.9.
+    rob.plan(1, 0.9, 0.0);
+    assert_eq!(rob.q_matrix[row][usize::from(a)], 7.5);
+}
+
+#[test]
+fn test_single_best_action_draws_nothing() {
+    let p = Percept::default();
+    let mut rob = Robot::with_seed(0.0, 12);
+    rob.q_matrix[rob.percept_map[&p]][usize::from(Action::MoveEast)] = 1.0;
+
+    let mut expected = rob.rng_snapshot();
+    let _: f32 = expected.random_range(0.0..1.0);
+    assert_eq!(rob.select_action(&p), Action::MoveEast);
+    assert_eq!(rob.rng_snapshot().random::<u64>(), expected.random::<u64>());
+
+    // The public lookup works on a shared robot, leaves its rng alone and
+    // settles ties on the lowest index.
+    let shared = &rob;
+    let before = shared.rng_snapshot();
+    assert_eq!(shared.max_action_for_percept(&p), (Action::MoveEast, 1.0));
+    assert_eq!(shared.rng_snapshot(), before);
+    rob.q_matrix[rob.percept_map[&p]][usize::from(Action::MoveSouth)] = 1.0;
+    for seed in 0..5 {
+        let mut other = Robot::with_seed(0.0, seed);
+        other.q_matrix = rob.q_matrix.clone();
+        assert_eq!(other.max_action_for_percept(&p), (Action::MoveSouth, 1.0));
+    }
+}
+
+#[test]
+fn test_step_env_with_breakdown() {
+    let mut env = Environment::deterministic(3, (1, 1), &[(1, 1)]);
+    env.pickup_success_prob = 0.0;
+    let (reward_amount, breakdown, p, done) = step_env_with_breakdown(&mut env, &Action::PickUpCan);
+    assert_eq!(breakdown.total(), reward_amount);
+    assert_eq!(breakdown.can_pickup, 0.0);
+    assert_eq!(p.current, LocationValue::Can);
+    assert!(!done);
+}
+
+#[test]
+fn test_train_flags_wasted_pickup_episodes() {
+    let params = TrainParams {
+        grid_dimension: 3,
+        can_count: CanCount::Fixed(0),
+        n_episodes: 2,
+        m_steps: 10,
+        time_episodes: false,
+        ..Default::default()
+    };
+
+    let mut picker = Robot::with_seed(0.0, 9);
+    for row in picker.q_matrix.iter_mut() {
+        row[usize::from(Action::PickUpCan)] = 100.0;
+    }
+    let records = train(&mut picker, &params).unwrap();
+    assert!(records.iter().all(|e| e.wasted_pickup_dominated));
+
+    let mut walker = Robot::with_seed(0.0, 9);
+    walker.exploration_weights = vec![1.0, 1.0, 1.0, 1.0, 0.0];
+    let records = train(&mut walker, &params).unwrap();
+    assert!(records.iter().all(|e| !e.wasted_pickup_dominated));
+}
+
+#[test]
+fn test_zero_step_episodes() {
+    let params = TrainParams {
+        grid_dimension: 3,
+        can_count: CanCount::Fixed(2),
+        n_episodes: 2,
+        m_steps: 0,
+        ..Default::default()
+    };
+    let records = train(&mut Robot::with_seed(0.1, 3), &params).unwrap();
+    assert!(records.iter().all(|e| e.mean_greedy_q == 0.0));
+    assert!(records.iter().all(|e| e.exploration_fraction == 0.0));
+}
+
+#[test]
+fn test_terminal_update_ignores_next_state() {
+    let mut env = Environment::new(3, 0, (0, 1));
+    env.edge_behavior = EdgeBehavior::Pit { penalty: -10.0 };
+    let start = env.create_percept();
+
+    let mut rob = Robot::with_seed(0.0, 4);
+    rob.previous_choice = Some((start.clone(), Action::MoveSouth));
+    let (reward_amount, next, done) = step_env(&mut env, &Action::MoveSouth);
+    assert!(done);
+    // Falling in leaves the robot where it was, so the next state is the
+    // start state, whose other actions look very valuable.
+    assert_eq!(next, start);
+    let row = rob.percept_map[&start];
+    rob.q_matrix[row] = vec![100.0, 0.0, 100.0, 100.0, 100.0];
+
+    rob.reward(reward_amount, 0.5, 0.9, &next, done);
+    assert_eq!(rob.q_matrix[row][usize::from(Action::MoveSouth)], -5.0);
 }
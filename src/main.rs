@@ -1,7 +1,15 @@
-use std::{fs::File, io::Write};
-
-use clap::Parser;
-use rl_agent::{Action, Environment, Robot};
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use clap::{Parser, ValueEnum};
+use rl_agent::{
+    Action, Environment, EpisodeRecord, INITIAL_EPSILON, Robot, TrainParams, TrainingArtifact,
+    train, write_episodes_jsonl,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -29,213 +37,150 @@ struct Args {
     /// Gamma
     #[arg(long, default_value_t = 0.9)]
     gamma: f32,
-}
-
-struct EpisodeRecord {
-    episode_id: usize,
-    episode_reward: f32,
-    crash_count: usize,
-    running_average: f32,
-}
 
-fn main() -> std::io::Result<()> {
-    let args = Args::parse();
+    /// Format of the episode output files
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
 
-    let mut robby = Robot::new(0.1);
+    /// Step through a single environment by hand instead of training
+    #[arg(long)]
+    interactive: bool,
 
-    let mut episodes: Vec<EpisodeRecord> = Vec::with_capacity(args.n_episodes);
+    /// Training artifact whose robot suggests an action at each interactive
+    /// step
+    #[arg(long, requires = "interactive")]
+    policy: Option<PathBuf>,
+}
 
-    for episode_id in 0..args.n_episodes {
-        let mut environment =
-            Environment::new_randomized(args.grid_dimensions, args.initial_can_count);
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Csv,
+    Json,
+}
 
-        let mut episode_reward: f32 = 0.0;
-        let mut episode_actions: Vec<Action> = Vec::new();
+/// A greedy robot acting on the final Q-matrix of a saved training artifact.
+fn load_policy(path: &PathBuf) -> io::Result<Robot> {
+    let artifact = TrainingArtifact::load(path)?;
+    let mut robot = Robot::new(0.0);
+    if artifact.final_q.len() != robot.q_matrix.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "policy has {} rows, expected {}",
+                artifact.final_q.len(),
+                robot.q_matrix.len()
+            ),
+        ));
+    }
+    robot.q_matrix = artifact.final_q;
+    Ok(robot)
+}
 
-        for _ in 0..args.m_steps {
-            let p = environment.create_percept();
-            let a = robby.select_action(&p);
-            let reward_amount = environment.calculate_reward(&a);
-            episode_reward += reward_amount;
-            environment.transition_state(&a);
-            let resulting_p = environment.create_percept();
-            robby.reward(reward_amount, args.eta, args.gamma, &resulting_p);
-            episode_actions.push(a);
+/// Let the user drive the robot around a random grid from stdin, optionally
+/// showing what a loaded policy would do.
+fn run_interactive(args: &Args) -> std::io::Result<()> {
+    let mut policy = args.policy.as_ref().map(load_policy).transpose()?;
+    let mut environment = Environment::new_randomized(args.grid_dimensions, args.initial_can_count);
+    let mut total_reward: f32 = 0.0;
+    let stdin = io::stdin();
+
+    loop {
+        let percept = environment.create_percept();
+        println!("{}", environment.render());
+        println!("{}", percept);
+        if let Some(robot) = &mut policy {
+            println!("Policy suggests: {}", robot.select_greedy_action(&percept));
         }
+        print!("Action [n/s/e/w/p, q to quit]: ");
+        io::stdout().flush()?;
 
-        let last_few: Vec<f32> = episodes[episode_id.saturating_sub(100)..episode_id]
-            .iter()
-            .map(|e| e.episode_reward)
-            .collect();
-        let mut sum: f32 = 0.0;
-        for record in &last_few {
-            sum += record;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 || line.trim() == "q" {
+            break;
         }
 
-        let running_average = sum / last_few.len() as f32;
-
-        let record = EpisodeRecord {
-            episode_id,
-            episode_reward,
-            crash_count: environment.crash_count,
-            running_average,
+        let Some(a) = Action::from_key(&line) else {
+            println!("Unknown action {:?}", line.trim());
+            continue;
         };
 
-        episodes.push(record);
+        let reward_amount = environment.calculate_reward(&a);
+        total_reward += reward_amount;
+        environment.transition_state(&a);
+        println!("Reward: {}, total: {}\n", reward_amount, total_reward);
 
-        // reduce epsilon every 50 episodes
-        if (episode_id + 1) % 50 == 0 {
-          robby.epsilon *= 0.99;
+        if environment.done {
+            println!("Episode over");
+            break;
         }
     }
 
-    let episode_file_path = "episodes.csv";
-    let mut episodes_file = File::create(episode_file_path)?;
+    Ok(())
+}
+
+fn write_episodes(path: &str, episodes: &[EpisodeRecord]) -> std::io::Result<()> {
+    let mut episodes_file = File::create(path)?;
     writeln!(
         episodes_file,
-        "episode_id,episode_reward,running_avg,crash_count"
+        "episode_id,episode_reward,running_avg,crash_count,duration_micros"
     )?;
 
     let episodes_string = episodes
         .iter()
         .map(|e| {
             format!(
-                "{},{},{},{}",
-                e.episode_id, e.episode_reward, e.running_average, e.crash_count
+                "{},{},{},{},{}",
+                e.episode_id, e.episode_reward, e.running_average, e.crash_count, e.duration_micros
             )
         })
         .collect::<Vec<String>>()
         .join("\n");
 
-    write!(episodes_file, "{}", episodes_string)?;
+    write!(episodes_file, "{}", episodes_string)
+}
 
-    let weight_file_path = "weights.csv";
-    let mut weights_file = File::create(weight_file_path)?;
-    write!(weights_file, "Current,North,South,East,West")?;
-    for i in 0..5 {
-        write!(weights_file, ",{}", Action::from(i))?;
-    }
-    writeln!(weights_file)?;
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
 
-    let x = robby
-        .percept_map
-        .iter()
-        .map(|(p, i)| {
-            format!(
-                "{},{},{},{},{},{},{},{},{},{}",
-                p.current,
-                p.north,
-                p.south,
-                p.east,
-                p.west,
-                robby.q_matrix[*i][0],
-                robby.q_matrix[*i][1],
-                robby.q_matrix[*i][2],
-                robby.q_matrix[*i][3],
-                robby.q_matrix[*i][4],
-            )
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
+    if args.interactive {
+        return Ok(run_interactive(&args)?);
+    }
 
-    write!(weights_file, "{}", x)?;
+    let mut robby = Robot::new(INITIAL_EPSILON);
+
+    let params = TrainParams {
+        grid_dimension: args.grid_dimensions,
+        can_count: args.initial_can_count.into(),
+        n_episodes: args.n_episodes,
+        m_steps: args.m_steps,
+        eta: args.eta,
+        gamma: args.gamma,
+        epsilon_decay: 0.99,
+        ..Default::default()
+    };
+
+    let episodes = train(&mut robby, &params)?;
+    match args.format {
+        OutputFormat::Csv => write_episodes("episodes.csv", &episodes)?,
+        OutputFormat::Json => write_episodes_jsonl("episodes.jsonl", &episodes)?,
+    }
+    robby.weights_csv("weights.csv")?;
 
     println!("Training complete, now testing");
 
-    episodes = Vec::with_capacity(args.n_episodes);
-
     robby.epsilon = 0.1;
 
-    for episode_id in 0..args.n_episodes {
-        let mut environment =
-            Environment::new_randomized(args.grid_dimensions, args.initial_can_count);
-
-        let mut episode_reward: f32 = 0.0;
-        let mut episode_actions: Vec<Action> = Vec::new();
-
-        for _ in 0..args.m_steps {
-            let p = environment.create_percept();
-            let a = robby.select_action(&p);
-            let reward_amount = environment.calculate_reward(&a);
-            episode_reward += reward_amount;
-            environment.transition_state(&a);
-            let resulting_p = environment.create_percept();
-            robby.reward(reward_amount, args.eta, args.gamma, &resulting_p);
-            episode_actions.push(a);
-        }
-
-        let last_few: Vec<f32> = episodes[episode_id.saturating_sub(100)..episode_id]
-            .iter()
-            .map(|e| e.episode_reward)
-            .collect();
-        let mut sum: f32 = 0.0;
-        for record in &last_few {
-            sum += record;
-        }
+    let test_params = TrainParams {
+        epsilon_decay: 1.0,
+        ..params
+    };
 
-        let running_average = sum / last_few.len() as f32;
-
-        let record = EpisodeRecord {
-            episode_id,
-            episode_reward,
-            crash_count: environment.crash_count,
-            running_average,
-        };
-
-        episodes.push(record);
-    }
-
-    let episode_file_path = "test_episodes.csv";
-    let mut episodes_file = File::create(episode_file_path)?;
-    writeln!(
-        episodes_file,
-        "episode_id,episode_reward,running_avg,crash_count"
-    )?;
-
-    let episodes_string = episodes
-        .iter()
-        .map(|e| {
-            format!(
-                "{},{},{},{}",
-                e.episode_id, e.episode_reward, e.running_average, e.crash_count
-            )
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    write!(episodes_file, "{}", episodes_string)?;
-
-    let weight_file_path = "test_weights.csv";
-    let mut weights_file = File::create(weight_file_path)?;
-    write!(weights_file, "Current,North,South,East,West")?;
-    for i in 0..5 {
-        write!(weights_file, ",{}", Action::from(i))?;
+    let episodes = train(&mut robby, &test_params)?;
+    match args.format {
+        OutputFormat::Csv => write_episodes("test_episodes.csv", &episodes)?,
+        OutputFormat::Json => write_episodes_jsonl("test_episodes.jsonl", &episodes)?,
     }
-    writeln!(weights_file)?;
-
-    let x = robby
-        .percept_map
-        .iter()
-        .map(|(p, i)| {
-            format!(
-                "{},{},{},{},{},{},{},{},{},{}",
-                p.current,
-                p.north,
-                p.south,
-                p.east,
-                p.west,
-                robby.q_matrix[*i][0],
-                robby.q_matrix[*i][1],
-                robby.q_matrix[*i][2],
-                robby.q_matrix[*i][3],
-                robby.q_matrix[*i][4],
-            )
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    write!(weights_file, "{}", x)?;
-
+    robby.weights_csv("test_weights.csv")?;
 
     Ok(())
 }